@@ -0,0 +1,63 @@
+//! The image transforms on a 1920x1080 capture, run with `cargo bench`
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+
+use nora::geometry::Rect;
+use nora::image::{ByteOrder, Tint};
+use nora::Frame;
+
+const REGION: Rect = Rect {
+    x: 0,
+    y: 0,
+    width: 1920,
+    height: 1080,
+};
+
+/// A depth 24 capture with 4 bytes per pixel and the usual stride of XGetImage
+fn frame() -> Frame {
+    let stride = REGION.width as usize * 4;
+    let data = (0..stride * REGION.height as usize)
+        .map(|i| (i * 7 % 251) as u8)
+        .collect();
+
+    Frame::new(data, REGION, stride, 24, 32).unwrap()
+}
+
+fn transforms(c: &mut Criterion) {
+    let source = frame();
+
+    c.bench_function("blur 8", |b| {
+        b.iter_batched_ref(frame, |frame| frame.blur(8), BatchSize::LargeInput)
+    });
+    c.bench_function("dim", |b| {
+        b.iter_batched_ref(frame, |frame| frame.dim(0.6, None), BatchSize::LargeInput)
+    });
+    c.bench_function("dim with tint", |b| {
+        let tint = Tint {
+            r: 0x20,
+            g: 0x40,
+            b: 0x80,
+            a: 0x60,
+        };
+        b.iter_batched_ref(
+            frame,
+            |frame| frame.dim(0.6, Some(tint)),
+            BatchSize::LargeInput,
+        )
+    });
+    c.bench_function("grayscale", |b| {
+        b.iter_batched_ref(frame, |frame| frame.grayscale(), BatchSize::LargeInput)
+    });
+    c.bench_function("downscale 0.5", |b| {
+        b.iter(|| source.downscale(black_box(0.5)))
+    });
+    c.bench_function("to_bytes rgba", |b| {
+        b.iter(|| source.to_bytes(black_box(ByteOrder::Rgba)))
+    });
+    c.bench_function("to_bytes bgr", |b| {
+        b.iter(|| source.to_bytes(black_box(ByteOrder::Bgr)))
+    });
+}
+
+criterion_group!(benches, transforms);
+criterion_main!(benches);
@@ -1,10 +1,35 @@
+// The pkg-config name, minimum version, the Cargo feature that needs it if it is optional
+// and the packages with the development files on Debian/Ubuntu and Fedora
+const LIBRARIES: &[(&str, &str, Option<&str>, &str, &str)] = &[
+    ("x11", "1.4.99.1", None, "libx11-dev", "libX11-devel"),
+    (
+        "xfixes",
+        "3.1.0",
+        Some("cursor"),
+        "libxfixes-dev",
+        "libXfixes-devel",
+    ),
+    ("xext", "1.3", None, "libxext-dev", "libXext-devel"),
+];
+
 fn main() {
-    pkg_config::Config::new()
-        .atleast_version("1.4.99.1")
-        .probe("x11")
-        .unwrap();
-    pkg_config::Config::new()
-        .atleast_version("3.1.0")
-        .probe("xfixes")
-        .unwrap();
+    for &(name, version, feature, debian, fedora) in LIBRARIES {
+        let feature = feature.map(|feature| format!("CARGO_FEATURE_{}", feature.to_uppercase()));
+        if feature.is_some_and(|feature| std::env::var_os(feature).is_none()) {
+            continue;
+        }
+
+        let probe = pkg_config::Config::new()
+            .atleast_version(version)
+            .probe(name);
+
+        if let Err(e) = probe {
+            eprintln!(
+                "nora needs {} {} or newer but pkg-config could not find it.\n\
+                 Installing {} on Debian and Ubuntu or {} on Fedora should fix that.\n\n{}",
+                name, version, debian, fedora, e
+            );
+            std::process::exit(1);
+        }
+    }
 }
@@ -0,0 +1,203 @@
+//! Serving a frame as `image/png` on the `CLIPBOARD` selection
+//!
+//! X has no clipboard storage, the owner sends the data whenever another client
+//! pastes. The image is only available while the `Clipboard` is alive and its
+//! events are handled, unless a clipboard manager copies it over before that.
+
+use std::os::raw::c_ulong;
+
+use anyhow::{bail, Context, Result};
+use log::{debug, info};
+use x11::xlib::{Atom, PropModeReplace, PropertyChangeMask, Time, Window, XA_ATOM};
+
+use crate::ffi::{Display, Event};
+use crate::geometry::Rect;
+use crate::image::{Format, Frame};
+
+// Larger transfers are split into chunks of this size with the INCR protocol
+const INCR_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Ownership of the `CLIPBOARD` selection with an encoded frame to hand out
+pub struct Clipboard<'a> {
+    display: &'a Display,
+    window: Window,
+    png: Vec<u8>,
+    clipboard: Atom,
+    targets: Atom,
+    image_png: Atom,
+    incr: Atom,
+    transfers: Vec<Transfer>,
+}
+
+/// An INCR transfer that continues whenever the requestor deletes the property
+struct Transfer {
+    requestor: Window,
+    property: Atom,
+    offset: usize,
+}
+
+impl<'a> Clipboard<'a> {
+    /// Takes over the clipboard with `frame`, `time` has to be the time of the triggering event
+    pub fn own(display: &'a Display, root: Window, frame: &Frame, time: Time) -> Result<Self> {
+        let mut png = Vec::new();
+        frame
+            .encode(Format::Png, &mut png)
+            .context("Failed to encode the image for the clipboard")?;
+
+        let [clipboard, targets, image_png, incr] = display
+            .intern_atoms(["CLIPBOARD", "TARGETS", "image/png", "INCR"], false)
+            .context("Failed to get the clipboard atoms")?;
+
+        // Selections need a window as owner, this one is never mapped and outlives the freeze
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            width: 1,
+            height: 1,
+        };
+        let window = display.create_window(root as i32, rect, 0, std::ptr::null_mut(), 0, 0);
+        let owned = Clipboard {
+            display,
+            window,
+            png,
+            clipboard,
+            targets,
+            image_png,
+            incr,
+            transfers: Vec::new(),
+        };
+
+        display.set_selection_owner(clipboard, window, time);
+        if display.get_selection_owner(clipboard) != window {
+            bail!("Failed to take over the clipboard");
+        }
+
+        info!(
+            "Copied {} bytes of PNG data to the clipboard",
+            owned.png.len()
+        );
+        Ok(owned)
+    }
+
+    /// Answers `event` if it concerns the clipboard, returns whether nora still owns it
+    pub fn handle(&mut self, event: &Event) -> bool {
+        match *event {
+            Event::SelectionRequest {
+                requestor,
+                selection,
+                target,
+                property,
+                time,
+            } => {
+                // Obsolete clients leave out the property and expect the target to be used
+                let property = if property == 0 { target } else { property };
+                let stored = selection == self.clipboard
+                    && match self.convert(requestor, target, property) {
+                        Ok(stored) => stored,
+                        Err(e) => {
+                            debug!("Failed to convert the clipboard for {}: {:#}", requestor, e);
+                            false
+                        }
+                    };
+
+                let property = if stored { property } else { 0 };
+                if let Err(e) = self
+                    .display
+                    .send_selection_notify(requestor, selection, target, property, time)
+                {
+                    debug!("{:#}", e);
+                }
+            }
+            Event::PropertyDelete { window, atom } => self.continue_transfer(window, atom),
+            Event::SelectionClear { selection } if selection == self.clipboard => {
+                info!("Another client took over the clipboard");
+                return false;
+            }
+            _ => (),
+        }
+
+        true
+    }
+
+    /// Stores `target` in `property` of `requestor`, returns false for unsupported targets
+    fn convert(&mut self, requestor: Window, target: Atom, property: Atom) -> Result<bool> {
+        let display = self.display;
+
+        if target == self.targets {
+            let targets = [self.targets, self.image_png];
+            display.try_change_property(requestor, property, XA_ATOM, PropModeReplace, &targets)?;
+            return Ok(true);
+        }
+
+        if target != self.image_png {
+            return Ok(false);
+        }
+
+        // Leave room for the rest of the ChangeProperty request
+        if self.png.len() < display.max_request_size() / 2 {
+            display.try_change_property(
+                requestor,
+                property,
+                self.image_png,
+                PropModeReplace,
+                &self.png,
+            )?;
+            return Ok(true);
+        }
+
+        debug!("Sending the clipboard to {} with INCR", requestor);
+        display.try_select_input(requestor, PropertyChangeMask)?;
+        display.try_change_property(
+            requestor,
+            property,
+            self.incr,
+            PropModeReplace,
+            &[self.png.len() as c_ulong],
+        )?;
+        self.transfers.push(Transfer {
+            requestor,
+            property,
+            offset: 0,
+        });
+
+        Ok(true)
+    }
+
+    /// Sends the next chunk once the requestor deleted the previous one
+    fn continue_transfer(&mut self, window: Window, atom: Atom) {
+        let index = self
+            .transfers
+            .iter()
+            .position(|transfer| transfer.requestor == window && transfer.property == atom);
+        let index = match index {
+            Some(index) => index,
+            None => return,
+        };
+        let transfer = &mut self.transfers[index];
+
+        // An empty chunk after the last one ends the transfer
+        let end = (transfer.offset + INCR_CHUNK_SIZE).min(self.png.len());
+        let chunk = &self.png[transfer.offset..end];
+        let finished = chunk.is_empty();
+        transfer.offset = end;
+
+        let sent =
+            self.display
+                .try_change_property(window, atom, self.image_png, PropModeReplace, chunk);
+        if let Err(e) = &sent {
+            debug!("Aborting the clipboard transfer to {}: {:#}", window, e);
+        }
+
+        if finished || sent.is_err() {
+            self.transfers.remove(index);
+            let _ = self.display.try_select_input(window, 0);
+        }
+    }
+}
+
+impl Drop for Clipboard<'_> {
+    fn drop(&mut self) {
+        self.display.destroy_window(self.window);
+        self.display.sync(false);
+    }
+}
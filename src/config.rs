@@ -0,0 +1,88 @@
+//! Defaults for some arguments from `~/.config/nora/config.toml`
+//!
+//! ```toml
+//! dim = 0.6
+//! delay = 0.5
+//! freeze_duration = 120
+//! monitor = "current"
+//! executable = ["maim", "-s", "image.png"]
+//! ```
+
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+
+/// The values of a config file, numbers are kept as strings and parsed like their arguments
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default, deserialize_with = "number")]
+    pub dim: Option<String>,
+    #[serde(default, deserialize_with = "number")]
+    pub delay: Option<String>,
+    #[serde(default, deserialize_with = "number")]
+    pub freeze_duration: Option<String>,
+    #[serde(default, deserialize_with = "monitor")]
+    pub monitor: Option<String>,
+    #[serde(default, deserialize_with = "executable")]
+    pub executable: Option<Vec<OsString>>,
+}
+
+/// `$XDG_CONFIG_HOME/nora/config.toml`, falling back to `~/.config`
+pub fn path() -> Option<PathBuf> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+
+    Some(config_home.join("nora").join("config.toml"))
+}
+
+/// Reads the config at `path`, a missing file is the same as an empty one
+pub fn load(path: &Path) -> Result<Config> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Config::default()),
+        Err(e) => return Err(anyhow!(e).context(format!("Failed to read {}", path.display()))),
+    };
+
+    toml::from_str(&text).with_context(|| anyhow!("Invalid config {}", path.display()))
+}
+
+/// Integers and floats alike
+fn number<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    let number = f64::deserialize(deserializer)?;
+    Ok(Some(number.to_string()))
+}
+
+/// A name or an index like --monitor accepts
+fn monitor<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Query {
+        Name(String),
+        Index(u32),
+    }
+
+    Ok(Some(match Query::deserialize(deserializer)? {
+        Query::Name(name) => name,
+        Query::Index(index) => index.to_string(),
+    }))
+}
+
+fn executable<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<OsString>>, D::Error> {
+    let parts = Vec::<String>::deserialize(deserializer)?;
+    if parts.is_empty() {
+        return Err(D::Error::invalid_length(
+            0,
+            &"the executable and its arguments",
+        ));
+    }
+
+    Ok(Some(parts.into_iter().map(OsString::from).collect()))
+}
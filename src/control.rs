@@ -0,0 +1,166 @@
+//! The Unix socket of --control-socket, which takes one command per line while the screen is
+//! frozen
+//!
+//! ```text
+//! recapture      freeze what is on screen now instead
+//! save PATH      save what the frozen window shows to PATH
+//! quit           unfreeze like Escape does
+//! ```
+//!
+//! Every command is answered with `ok` or `error: ` and the reason on a line of its own.
+
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::{debug, info};
+
+pub enum Command {
+    Recapture,
+    Save(PathBuf),
+    Quit,
+}
+
+/// The listening socket and its connected clients, the socket file is removed when dropped
+pub struct ControlSocket {
+    listener: UnixListener,
+    path: PathBuf,
+    clients: Vec<Client>,
+}
+
+struct Client {
+    stream: UnixStream,
+    // Bytes read after the last complete line
+    pending: Vec<u8>,
+}
+
+impl ControlSocket {
+    /// Listens on `path`, replacing a socket left behind by a nora that did not exit cleanly
+    pub fn bind(path: &Path) -> Result<Self> {
+        let stale = match std::fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => UnixStream::connect(path).is_err(),
+            Ok(_) => bail!("{} exists and is not a socket", path.display()),
+            Err(_) => false,
+        };
+        if stale {
+            debug!("Removing the stale socket {}", path.display());
+            std::fs::remove_file(path)
+                .with_context(|| anyhow!("Failed to remove {}", path.display()))?;
+        }
+
+        let listener = UnixListener::bind(path)
+            .with_context(|| anyhow!("Failed to listen on {}", path.display()))?;
+        listener
+            .set_nonblocking(true)
+            .context("Failed to make the control socket non-blocking")?;
+        info!("Listening for commands on {}", path.display());
+
+        Ok(ControlSocket {
+            listener,
+            path: path.to_owned(),
+            clients: Vec::new(),
+        })
+    }
+
+    /// The descriptors of the socket and its clients, one of them is readable when `poll` has
+    /// something to do
+    pub fn fds(&self) -> Vec<RawFd> {
+        std::iter::once(self.listener.as_raw_fd())
+            .chain(self.clients.iter().map(|client| client.stream.as_raw_fd()))
+            .collect()
+    }
+
+    /// Accepts new clients and passes every command they sent to `handle` without blocking
+    ///
+    /// What `handle` returns is sent back as the answer.
+    pub fn poll(&mut self, mut handle: impl FnMut(Command) -> Result<()>) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => match stream.set_nonblocking(true) {
+                    Ok(()) => self.clients.push(Client {
+                        stream,
+                        pending: Vec::new(),
+                    }),
+                    Err(e) => debug!("Dropping a control client: {}", e),
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    debug!("Failed to accept a control client: {}", e);
+                    break;
+                }
+            }
+        }
+
+        self.clients.retain_mut(|client| {
+            let mut open = client.read();
+
+            while let Some(end) = client.pending.iter().position(|&byte| byte == b'\n') {
+                let line: Vec<u8> = client.pending.drain(..=end).collect();
+                let line = String::from_utf8_lossy(&line);
+                let line = line.trim();
+                if line.is_empty() {
+                    continue;
+                }
+
+                debug!("Control command {:?}", line);
+                let answer = match parse(line).and_then(&mut handle) {
+                    Ok(()) => "ok".to_string(),
+                    Err(e) => format!("error: {:#}", e).replace('\n', " "),
+                };
+
+                // The answers are short, a client that does not read them is dropped, but the
+                // commands it already sent still run
+                if client
+                    .stream
+                    .write_all(format!("{}\n", answer).as_bytes())
+                    .is_err()
+                {
+                    open = false;
+                }
+            }
+
+            open
+        });
+    }
+}
+
+impl Client {
+    /// Reads everything available, returns whether the client is still connected
+    fn read(&mut self) -> bool {
+        let mut buffer = [0; 1024];
+
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return false,
+                Ok(n) => self.pending.extend_from_slice(&buffer[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(_) => return false,
+            }
+        }
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn parse(line: &str) -> Result<Command> {
+    let (name, argument) = line
+        .split_once(char::is_whitespace)
+        .map_or((line, ""), |(name, argument)| (name, argument.trim()));
+
+    match (name, argument) {
+        ("recapture", "") => Ok(Command::Recapture),
+        ("quit", "") => Ok(Command::Quit),
+        ("save", "") => bail!("save needs a path"),
+        ("save", path) => Ok(Command::Save(PathBuf::from(path))),
+        ("recapture" | "quit", _) => bail!("{} takes no arguments", name),
+        _ => bail!("unknown command {}", name),
+    }
+}
@@ -1,59 +1,300 @@
-use std::ffi::CString;
+use std::cell::{Cell, OnceCell};
+use std::ffi::{CStr, CString};
+use std::mem::MaybeUninit;
 use std::ops::Deref;
+use std::os::fd::RawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
-use anyhow::{bail, Result};
-use libc::c_void;
+use anyhow::{anyhow, bail, Context, Result};
+use libc::{c_char, c_int, c_long, c_ulong, c_void, IPC_CREAT, IPC_PRIVATE, IPC_RMID};
 
+#[cfg(feature = "cursor")]
 use x11::xfixes::XFixesCursorImage;
 use x11::xlib::False as XFalse;
 use x11::xlib::True as XTrue;
 use x11::xlib::{
-    Atom, CWBackPixmap, CWOverrideRedirect, Drawable, Pixmap, Time, Window, XChangeProperty,
-    XConfigureWindow, XCreateGC, XCreatePixmap, XCreateWindow, XFreeGC, XFreePixmap, XGetImage,
-    XImage, XInternAtom, XMapWindow, XPutImage, XScreenOfDisplay, XSetInputFocus,
-    XSetWindowAttributes, XSync, GC,
+    AllocNone, Atom, ButtonPress, ButtonRelease, CWBackPixmap, CWBorderPixel, CWColormap,
+    CWOverrideRedirect, ClientMessage, Colormap, Drawable, Expose, GrabModeAsync, InputOutput,
+    IsViewable, KeyPress, KeySym, MapNotify, MotionNotify, Pixmap, PropertyDelete, PropertyNotify,
+    SelectionClear, SelectionNotify, SelectionRequest, SubstructureNotifyMask,
+    SubstructureRedirectMask, Time, TrueColor, Visual, Window, XChangeProperty,
+    XChangeWindowAttributes, XCheckTypedWindowEvent, XClearArea, XClientMessageEvent,
+    XCloseDisplay, XConfigureWindow, XConnectionNumber, XCopyArea, XCreateColormap, XCreateGC,
+    XCreateImage, XCreatePixmap, XCreateWindow, XDefaultScreen, XDestroyImage, XDestroyWindow,
+    XDrawRectangle, XErrorEvent, XEvent, XEventsQueued, XExtendedMaxRequestSize, XFlush,
+    XFreeColormap, XFreeGC, XFreePixmap, XGetImage, XGetInputFocus, XGetSelectionOwner,
+    XGetWindowAttributes, XGetWindowProperty, XGrabKeyboard, XGrabPointer, XImage, XInternAtom,
+    XInternAtoms, XLookupKeysym, XMapWindow, XMatchVisualInfo, XMaxRequestSize, XNextEvent,
+    XPending, XPutImage, XQueryExtension, XQueryPointer, XResetScreenSaver, XScreenCount,
+    XScreenNumberOfScreen, XScreenOfDisplay, XSelectInput, XSelectionEvent, XSendEvent,
+    XSetErrorHandler, XSetForeground, XSetInputFocus, XSetSelectionOwner, XSetSubwindowMode,
+    XSetWindowAttributes, XSetWindowBackgroundPixmap, XSetWindowBorder, XSync,
+    XTranslateCoordinates, XUngrabKeyboard, XUngrabPointer, XUnmapWindow, XVisualInfo,
+    XWarpPointer, XWindowAttributes, ZPixmap, GC, XA_WINDOW,
 };
+use x11::xshm::{XShmAttach, XShmCreateImage, XShmDetach, XShmGetImage, XShmQueryExtension};
+use x11_dl::xinerama::Xlib as Xinerama;
+use x11_dl::xrandr::{
+    RRScreenChangeNotify, RRScreenChangeNotifyMask, RR_Connected, XRRScreenChangeNotifyEvent,
+    Xrandr,
+};
+use x11_dl::xss::Xss;
+
+use crate::geometry::Rect;
+use crate::image::Frame;
+
+// Part of libXext but not of the x11 crate
+extern "C" {
+    fn XShmQueryVersion(
+        display: *mut x11::xlib::Display,
+        major: *mut c_int,
+        minor: *mut c_int,
+        shared_pixmaps: *mut x11::xlib::Bool,
+    ) -> x11::xlib::Bool;
+}
+
+// The XEventsQueued mode that only counts events Xlib already read, missing in the x11 crate
+const QUEUED_ALREADY: c_int = 0;
+
+// The x11 crate declares the minor version of XFixesQueryVersion as const, even though
+// libXfixes writes it
+#[cfg(feature = "cursor")]
+fn writable(minor: &mut c_int) -> *const c_int {
+    minor
+}
+
+// Set by `error_handler` when a request failed while errors are trapped
+static X_ERROR: AtomicBool = AtomicBool::new(false);
 
 pub struct Display {
     ptr: *mut x11::xlib::_XDisplay,
     has_xfixes: bool,
+    has_shm: bool,
+    screen_change_event: Cell<Option<c_int>>,
+    // Has to stay loaded until the display is closed, libXrandr registers hooks with Xlib
+    xrandr: OnceCell<Xrandr>,
 }
 
 impl Display {
-    pub fn open(name: Option<&str>) -> Self {
-        let name_ptr = if let Some(name) = name.map(|s| CString::new(s).unwrap()) {
-            name.as_ptr()
-        } else {
-            std::ptr::null()
-        };
+    pub fn open(name: Option<&str>) -> Result<Self> {
+        let name = name
+            .map(CString::new)
+            .transpose()
+            .context("Display names can not contain NUL bytes")?;
+        let name_ptr = name.as_ref().map_or(std::ptr::null(), |name| name.as_ptr());
 
         let ptr = unsafe { x11::xlib::XOpenDisplay(name_ptr) };
+        if ptr.is_null() {
+            let name = unsafe { CStr::from_ptr(x11::xlib::XDisplayName(name_ptr)) };
+            if name.to_bytes().is_empty() {
+                if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+                    bail!(
+                        "Could not open a display, this is a Wayland session without XWayland \
+                         and only X11 is supported"
+                    );
+                }
 
-        assert!(!ptr.is_null());
+                bail!("Could not open a display, no name was given and DISPLAY is not set");
+            }
 
-        let has_xfixes = unsafe { x11::xfixes::XFixesQueryVersion(ptr, &mut 2, &mut 0) } == XTrue;
+            bail!(
+                "Could not open display {}, check that an X server is running and accepts \
+                 connections from this user",
+                name.to_string_lossy()
+            );
+        }
 
-        Display { ptr, has_xfixes }
+        #[cfg(feature = "cursor")]
+        let has_xfixes = {
+            let mut minor = 0;
+            unsafe { x11::xfixes::XFixesQueryVersion(ptr, &mut 2, writable(&mut minor)) == XTrue }
+        };
+        #[cfg(not(feature = "cursor"))]
+        let has_xfixes = false;
+
+        let has_shm = unsafe { XShmQueryExtension(ptr) } == XTrue;
+
+        Ok(Display {
+            ptr,
+            has_xfixes,
+            has_shm,
+            screen_change_event: Cell::new(None),
+            xrandr: OnceCell::new(),
+        })
     }
 
-    pub fn create_gc(&self, drawable: u64) -> GC {
-        unsafe { XCreateGC(self.ptr, drawable, 0, (&mut []).as_mut_ptr()) }
+    pub(crate) fn create_gc(&self, drawable: u64) -> GC {
+        unsafe { XCreateGC(self.ptr, drawable, 0, std::ptr::null_mut()) }
     }
 
-    pub fn create_pixmap(&self, drawable: u64, width: u32, height: u32, depth: u32) -> Pixmap {
-        unsafe { XCreatePixmap(self.ptr, drawable, width, height, depth) }
+    pub(crate) fn set_foreground(&self, gc: GC, pixel: c_ulong) {
+        unsafe { XSetForeground(self.ptr, gc, pixel) };
     }
 
-    pub fn create_window(
+    /// Draws the one pixel wide outline just inside `rect`
+    pub(crate) fn draw_rectangle(&self, drawable: Drawable, gc: GC, rect: Rect) {
+        unsafe {
+            XDrawRectangle(
+                self.ptr,
+                drawable,
+                gc,
+                rect.x as i32,
+                rect.y as i32,
+                rect.width as u32 - 1,
+                rect.height as u32 - 1,
+            )
+        };
+    }
+
+    pub(crate) fn set_subwindow_mode(&self, gc: GC, mode: i32) {
+        unsafe { XSetSubwindowMode(self.ptr, gc, mode) };
+    }
+
+    /// Copies `rect` of `src` to `(x, y)` in `dst` on the server
+    pub(crate) fn copy_area(
         &self,
-        parent: i32,
+        src: Drawable,
+        dst: Drawable,
+        gc: GC,
+        rect: Rect,
         x: i32,
         y: i32,
-        width: i32,
-        height: i32,
-        border_width: i32,
+    ) {
+        unsafe {
+            XCopyArea(
+                self.ptr,
+                src,
+                dst,
+                gc,
+                rect.x as i32,
+                rect.y as i32,
+                rect.width as u32,
+                rect.height as u32,
+                x,
+                y,
+            )
+        };
+    }
+
+    /// Reports the extensions nora uses and their versions on the server
+    pub fn extensions(&self) -> Vec<Extension> {
+        let (mut major, mut minor) = (0, 0);
+        let version = |found: bool, major: c_int, minor: c_int| found.then_some((major, minor));
+
+        // Without the cursor feature nora is not linked against libXfixes
+        #[cfg(feature = "cursor")]
+        let xfixes = {
+            let found = unsafe {
+                x11::xfixes::XFixesQueryVersion(self.ptr, &mut major, writable(&mut minor))
+            };
+            version(found == XTrue, major, minor)
+        };
+        #[cfg(not(feature = "cursor"))]
+        let xfixes = None;
+
+        let mut shared_pixmaps = XFalse;
+        let found =
+            unsafe { XShmQueryVersion(self.ptr, &mut major, &mut minor, &mut shared_pixmaps) };
+        let shm = version(found == XTrue, major, minor);
+
+        let randr = self.xrandr().ok().and_then(|xrandr| {
+            let dpy = self.ptr as *mut x11_dl::xlib::Display;
+            let found = unsafe { (xrandr.XRRQueryVersion)(dpy, &mut major, &mut minor) };
+            version(found != 0, major, minor)
+        });
+
+        let xinerama = Xinerama::open().ok().and_then(|xinerama| {
+            let dpy = self.ptr as *mut x11_dl::xlib::Display;
+            let found = unsafe { (xinerama.XineramaQueryVersion)(dpy, &mut major, &mut minor) };
+            version(found != 0, major, minor)
+        });
+
+        let screensaver = Xss::open().ok().and_then(|xss| {
+            let dpy = self.ptr as *mut x11_dl::xlib::Display;
+            let found = unsafe { (xss.XScreenSaverQueryVersion)(dpy, &mut major, &mut minor) };
+            version(found != 0, major, minor)
+        });
+
+        // It has no version request and only ever had version 2.0
+        let big_requests = self.has_extension("BIG-REQUESTS").then_some((2, 0));
+
+        vec![
+            Extension {
+                name: "XFIXES",
+                version: xfixes,
+            },
+            Extension {
+                name: "MIT-SHM",
+                version: shm,
+            },
+            Extension {
+                name: "RANDR",
+                version: randr,
+            },
+            Extension {
+                name: "XINERAMA",
+                version: xinerama,
+            },
+            Extension {
+                name: "MIT-SCREEN-SAVER",
+                version: screensaver,
+            },
+            Extension {
+                name: "BIG-REQUESTS",
+                version: big_requests,
+            },
+        ]
+    }
+
+    fn has_extension(&self, name: &str) -> bool {
+        let name = CString::new(name).expect("extension names do not contain NUL bytes");
+        let (mut opcode, mut event, mut error) = (0, 0, 0);
+
+        let found = unsafe {
+            XQueryExtension(self.ptr, name.as_ptr(), &mut opcode, &mut event, &mut error)
+        };
+        found == XTrue
+    }
+
+    pub fn has_xfixes(&self) -> bool {
+        self.has_xfixes
+    }
+
+    /// Creates a pixmap and waits for the server to allocate it, which fails with BadAlloc
+    /// when it runs out of memory for large screens
+    pub fn create_pixmap(
+        &self,
+        drawable: u64,
+        width: u32,
+        height: u32,
+        depth: u32,
+    ) -> Result<Pixmap> {
+        let mut pixmap = 0;
+        if self.trap_errors(|| {
+            pixmap = unsafe { XCreatePixmap(self.ptr, drawable, width, height, depth) };
+        }) {
+            bail!(
+                "Failed to create a {}x{} pixmap of depth {}",
+                width,
+                height,
+                depth
+            );
+        }
+
+        Ok(pixmap)
+    }
+
+    /// Creates an override-redirect InputOutput window without a border
+    ///
+    /// `depth`, `visual` and `colormap` are copied from the parent if they are 0 or null.
+    pub(crate) fn create_window(
+        &self,
+        parent: i32,
+        rect: Rect,
         depth: i32,
-        class: i32,
+        visual: *mut Visual,
+        colormap: Colormap,
         pixmap: Pixmap,
     ) -> Window {
         let mut attributes = XSetWindowAttributes {
@@ -70,7 +311,8 @@ impl Display {
             event_mask: 0,
             do_not_propagate_mask: 0,
             override_redirect: XTrue,
-            colormap: 0,
+            colormap,
+            // Without CWCursor the window uses the root cursor, so the live one stays visible
             cursor: 0,
         };
 
@@ -78,24 +320,116 @@ impl Display {
             XCreateWindow(
                 self.ptr,
                 parent as u64,
-                x,
-                y,
-                width as u32,
-                height as u32,
-                border_width as u32,
+                rect.x as i32,
+                rect.y as i32,
+                rect.width as u32,
+                rect.height as u32,
+                0,
                 depth,
-                class as u32,
-                std::ptr::null_mut(),
-                CWOverrideRedirect | CWBackPixmap,
+                InputOutput as u32,
+                visual,
+                // Windows with another visual than their parent need a colormap and border pixel
+                CWOverrideRedirect | CWBackPixmap | CWBorderPixel | CWColormap,
                 &mut attributes,
             )
         }
     }
 
+    pub(crate) fn create_colormap(&self, window: Window, visual: *mut Visual) -> Colormap {
+        unsafe { XCreateColormap(self.ptr, window, visual, AllocNone) }
+    }
+
+    pub fn free_colormap(&self, colormap: Colormap) {
+        unsafe { XFreeColormap(self.ptr, colormap) };
+    }
+
+    /// Finds a TrueColor visual with `depth` on `screen`
+    pub fn match_visual(&self, screen: &Screen, depth: i32) -> Result<*mut Visual> {
+        let mut info = MaybeUninit::<XVisualInfo>::zeroed();
+        let found = unsafe {
+            XMatchVisualInfo(
+                self.ptr,
+                XScreenNumberOfScreen(screen.ptr),
+                depth,
+                TrueColor,
+                info.as_mut_ptr(),
+            )
+        };
+        if found == 0 {
+            bail!(
+                "The screen has no TrueColor visual with a depth of {}",
+                depth
+            );
+        }
+
+        Ok(unsafe { info.assume_init() }.visual)
+    }
+
+    pub fn destroy_window(&self, w: Window) {
+        unsafe { XDestroyWindow(self.ptr, w) };
+    }
+
+    /// Whether the window manager leaves `w` alone, only takes effect when it is mapped next
+    pub fn set_override_redirect(&self, w: Window, override_redirect: bool) {
+        let mut attributes: XSetWindowAttributes = unsafe { std::mem::zeroed() };
+        attributes.override_redirect = override_redirect as i32;
+        unsafe { XChangeWindowAttributes(self.ptr, w, CWOverrideRedirect, &mut attributes) };
+    }
+
+    /// Surrounds the inside of `w` with a border of `width` pixels, `inside` is where the
+    /// window was created and where its contents stay
+    pub fn set_window_border(&self, w: Window, inside: Rect, width: u16, pixel: c_ulong) {
+        let (x, y) = inside.border_origin(width);
+        let mut changes = x11::xlib::XWindowChanges {
+            x,
+            y,
+            width: 0,
+            height: 0,
+            border_width: width as i32,
+            sibling: 0,
+            stack_mode: 0,
+        };
+        let mask = x11::xlib::CWX | x11::xlib::CWY | x11::xlib::CWBorderWidth;
+
+        unsafe {
+            XSetWindowBorder(self.ptr, w, pixel);
+            XConfigureWindow(self.ptr, w, mask as u32, &mut changes);
+        }
+    }
+
     pub fn map_window(&self, w: Window) {
         unsafe { XMapWindow(self.ptr, w) };
     }
 
+    /// Waits up to `timeout` for `w` to be mapped, which needs StructureNotifyMask on it
+    ///
+    /// Windows without override-redirect are only mapped once the window manager handled the
+    /// map request.
+    pub fn wait_for_map(&self, w: Window, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut event = XEvent { pad: [0; 24] };
+
+        loop {
+            if unsafe { XCheckTypedWindowEvent(self.ptr, w, MapNotify, &mut event) } == XTrue {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    pub fn unmap_window(&self, w: Window) {
+        unsafe { XUnmapWindow(self.ptr, w) };
+    }
+
+    /// Replaces the background of `w`, which only shows once the window is cleared
+    pub fn set_window_background(&self, w: Window, pixmap: Pixmap) {
+        unsafe { XSetWindowBackgroundPixmap(self.ptr, w, pixmap) };
+    }
+
     pub fn set_stack_mode(&self, w: Window, mode: i32) {
         let mut changes = x11::xlib::XWindowChanges {
             x: 0,
@@ -117,6 +451,40 @@ impl Display {
         Ok(unsafe { XInternAtom(self.ptr, atom_name.as_ptr(), only_if_exists) })
     }
 
+    /// Interns all atoms in a single round-trip instead of one per atom
+    pub fn intern_atoms<const N: usize>(
+        &self,
+        atom_names: [&str; N],
+        only_if_exists: bool,
+    ) -> Result<[Atom; N]> {
+        let only_if_exists = if only_if_exists { XTrue } else { XFalse };
+        let atom_names = atom_names
+            .iter()
+            .map(|&name| CString::new(name))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut name_ptrs: Vec<*mut c_char> = atom_names
+            .iter()
+            .map(|name| name.as_ptr() as *mut c_char)
+            .collect();
+
+        // Atoms that do not exist are left as None with only_if_exists
+        let mut atoms = [0; N];
+        let status = unsafe {
+            XInternAtoms(
+                self.ptr,
+                name_ptrs.as_mut_ptr(),
+                N as c_int,
+                only_if_exists,
+                atoms.as_mut_ptr(),
+            )
+        };
+        if status == 0 && only_if_exists == XFalse {
+            bail!("Failed to intern the atoms {:?}", atom_names);
+        }
+
+        Ok(atoms)
+    }
+
     pub fn change_property<T>(
         &self,
         window: Window,
@@ -125,7 +493,13 @@ impl Display {
         mode: i32,
         data: &[T],
     ) {
-        let format = std::mem::size_of::<T>() * 8;
+        // Xlib expects the data of 32 bit properties as an array of longs
+        let format = match std::mem::size_of::<T>() {
+            1 => 8,
+            2 => 16,
+            size if size == std::mem::size_of::<c_ulong>() => 32,
+            size => panic!("Properties can not consist of {} byte values", size),
+        };
         let data_len = data.len();
         let data_ptr = data.as_ptr();
         unsafe {
@@ -142,81 +516,942 @@ impl Display {
         };
     }
 
+    /// Like `change_property` but fails instead of exiting if the window is gone
+    pub fn try_change_property<T>(
+        &self,
+        window: Window,
+        property: Atom,
+        typ: Atom,
+        mode: i32,
+        data: &[T],
+    ) -> Result<()> {
+        if self.trap_errors(|| self.change_property(window, property, typ, mode, data)) {
+            bail!("Failed to change a property of window {}", window);
+        }
+
+        Ok(())
+    }
+
     pub fn set_input_focus(&self, focus: Window, revert_to: i32, time: Time) {
         unsafe { XSetInputFocus(self.ptr, focus, revert_to, time) };
     }
 
+    /// Like `set_input_focus` but fails instead of exiting if the window is gone
+    pub fn try_set_input_focus(&self, focus: Window, revert_to: i32, time: Time) -> Result<()> {
+        if self.trap_errors(|| self.set_input_focus(focus, revert_to, time)) {
+            bail!("Failed to focus window {}", focus);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the focused window and what the focus reverts to
+    pub fn get_input_focus(&self) -> (Window, i32) {
+        let (mut focus, mut revert_to) = (0, 0);
+        unsafe { XGetInputFocus(self.ptr, &mut focus, &mut revert_to) };
+
+        (focus, revert_to)
+    }
+
+    pub fn select_input(&self, w: Window, event_mask: i64) {
+        unsafe { XSelectInput(self.ptr, w, event_mask) };
+    }
+
+    /// Like `select_input` but fails instead of exiting if the window is gone
+    pub fn try_select_input(&self, w: Window, event_mask: i64) -> Result<()> {
+        if self.trap_errors(|| self.select_input(w, event_mask)) {
+            bail!("Failed to select the input of window {}", w);
+        }
+
+        Ok(())
+    }
+
+    pub fn set_selection_owner(&self, selection: Atom, owner: Window, time: Time) {
+        unsafe { XSetSelectionOwner(self.ptr, selection, owner, time) };
+    }
+
+    pub fn get_selection_owner(&self, selection: Atom) -> Window {
+        unsafe { XGetSelectionOwner(self.ptr, selection) }
+    }
+
+    /// Tells `requestor` that the selection was stored in `property`, or refused if it is 0
+    pub fn send_selection_notify(
+        &self,
+        requestor: Window,
+        selection: Atom,
+        target: Atom,
+        property: Atom,
+        time: Time,
+    ) -> Result<()> {
+        let mut event = XEvent {
+            selection: XSelectionEvent {
+                type_: SelectionNotify,
+                serial: 0,
+                send_event: XTrue,
+                display: self.ptr,
+                requestor,
+                selection,
+                target,
+                property,
+                time,
+            },
+        };
+
+        let failed = self.trap_errors(|| unsafe {
+            XSendEvent(self.ptr, requestor, XFalse, 0, &mut event);
+        });
+        if failed {
+            bail!("Failed to notify window {} about the selection", requestor);
+        }
+
+        Ok(())
+    }
+
+    /// Sends a 32 bit client message about `w` to the root window, how EWMH clients ask the
+    /// window manager to change a managed window
+    pub fn send_client_message(
+        &self,
+        root: Window,
+        w: Window,
+        message_type: Atom,
+        data: [c_long; 5],
+    ) -> Result<()> {
+        let mut event = XEvent {
+            client_message: XClientMessageEvent {
+                type_: ClientMessage,
+                serial: 0,
+                send_event: XTrue,
+                display: self.ptr,
+                window: w,
+                message_type,
+                format: 32,
+                data: data.into(),
+            },
+        };
+
+        let mask = SubstructureRedirectMask | SubstructureNotifyMask;
+        let failed = self.trap_errors(|| unsafe {
+            XSendEvent(self.ptr, root, XFalse, mask, &mut event);
+        });
+        if failed {
+            bail!("Failed to send a client message about window {}", w);
+        }
+
+        Ok(())
+    }
+
+    pub fn grab_keyboard(&self, grab_window: Window, time: Time) -> i32 {
+        unsafe {
+            XGrabKeyboard(
+                self.ptr,
+                grab_window,
+                XFalse,
+                GrabModeAsync,
+                GrabModeAsync,
+                time,
+            )
+        }
+    }
+
+    pub fn ungrab_keyboard(&self, time: Time) {
+        unsafe { XUngrabKeyboard(self.ptr, time) };
+    }
+
+    pub fn grab_pointer(&self, grab_window: Window, event_mask: i64, time: Time) -> i32 {
+        unsafe {
+            XGrabPointer(
+                self.ptr,
+                grab_window,
+                XFalse,
+                event_mask as u32,
+                GrabModeAsync,
+                GrabModeAsync,
+                0,
+                0,
+                time,
+            )
+        }
+    }
+
+    pub fn ungrab_pointer(&self, time: Time) {
+        unsafe { XUngrabPointer(self.ptr, time) };
+    }
+
+    /// Blocks until the next event nora cares about
+    pub fn next_event(&self) -> Event {
+        let mut event = XEvent { pad: [0; 24] };
+
+        loop {
+            unsafe { XNextEvent(self.ptr, &mut event) };
+
+            if let Some(event) = self.translate_event(&mut event) {
+                return event;
+            }
+        }
+    }
+
+    /// The file descriptor of the connection, readable when the server sent something
+    pub fn connection_fd(&self) -> RawFd {
+        unsafe { XConnectionNumber(self.ptr) }
+    }
+
+    /// Whether Xlib already read events that are waiting in its queue, which the connection
+    /// does not show as readable anymore
+    pub fn has_queued_events(&self) -> bool {
+        unsafe { XEventsQueued(self.ptr, QUEUED_ALREADY) > 0 }
+    }
+
+    /// Returns the next queued event nora cares about without blocking
+    pub fn poll_event(&self) -> Option<Event> {
+        let mut event = XEvent { pad: [0; 24] };
+
+        while unsafe { XPending(self.ptr) } > 0 {
+            unsafe { XNextEvent(self.ptr, &mut event) };
+
+            if let Some(event) = self.translate_event(&mut event) {
+                return Some(event);
+            }
+        }
+
+        None
+    }
+
+    fn translate_event(&self, event: &mut XEvent) -> Option<Event> {
+        let kind = event.get_type();
+        if kind == KeyPress {
+            return Some(Event::KeyPress {
+                keysym: unsafe { XLookupKeysym(&mut event.key, 0) },
+                time: unsafe { event.key.time },
+            });
+        }
+
+        if kind == ButtonPress || kind == ButtonRelease {
+            let button = unsafe { &event.button };
+            return Some(Event::Button {
+                button: button.button,
+                pressed: kind == ButtonPress,
+                x: button.x_root,
+                y: button.y_root,
+            });
+        }
+
+        if kind == MotionNotify {
+            let motion = unsafe { &event.motion };
+            return Some(Event::Motion {
+                x: motion.x_root,
+                y: motion.y_root,
+            });
+        }
+
+        if kind == Expose {
+            let expose = unsafe { &event.expose };
+            return Some(Event::Expose {
+                rect: Rect {
+                    x: expose.x as i16,
+                    y: expose.y as i16,
+                    width: expose.width as u16,
+                    height: expose.height as u16,
+                },
+            });
+        }
+
+        if kind == SelectionRequest {
+            let request = unsafe { &event.selection_request };
+            return Some(Event::SelectionRequest {
+                requestor: request.requestor,
+                selection: request.selection,
+                target: request.target,
+                property: request.property,
+                time: request.time,
+            });
+        }
+
+        if kind == SelectionClear {
+            let clear = unsafe { &event.selection_clear };
+            return Some(Event::SelectionClear {
+                selection: clear.selection,
+            });
+        }
+
+        if kind == PropertyNotify {
+            let notify = unsafe { &event.property };
+            if notify.state == PropertyDelete {
+                return Some(Event::PropertyDelete {
+                    window: notify.window,
+                    atom: notify.atom,
+                });
+            }
+        }
+
+        if Some(kind) == self.screen_change_event.get() {
+            let change = unsafe { &*(event as *const XEvent as *const XRRScreenChangeNotifyEvent) };
+            return Some(Event::ScreenChange {
+                width: change.width,
+                height: change.height,
+            });
+        }
+
+        None
+    }
+
+    /// Reports changes of the screen size as `Event::ScreenChange`
+    pub fn select_screen_change(&self, root: Window) -> Result<()> {
+        let xrandr = self.xrandr()?;
+        let dpy = self.ptr as *mut x11_dl::xlib::Display;
+
+        let mut event_base = 0;
+        let mut error_base = 0;
+        if unsafe { (xrandr.XRRQueryExtension)(dpy, &mut event_base, &mut error_base) } == XFalse {
+            bail!("The RandR extension is not available");
+        }
+
+        unsafe { (xrandr.XRRSelectInput)(dpy, root, RRScreenChangeNotifyMask) };
+        self.screen_change_event
+            .set(Some(event_base + RRScreenChangeNotify));
+
+        Ok(())
+    }
+
+    /// Loads libXrandr on first use
+    fn xrandr(&self) -> Result<&Xrandr> {
+        if let Some(xrandr) = self.xrandr.get() {
+            return Ok(xrandr);
+        }
+
+        let xrandr = Xrandr::open().context("Failed to load libXrandr")?;
+        Ok(self.xrandr.get_or_init(|| xrandr))
+    }
+
+    /// Runs `f` and syncs, returning whether any request failed
+    ///
+    /// Xlib's default error handler exits the process, this is for requests
+    /// whose failure can be handled.
+    fn trap_errors(&self, f: impl FnOnce()) -> bool {
+        X_ERROR.store(false, Ordering::SeqCst);
+
+        unsafe {
+            let old_handler = XSetErrorHandler(Some(error_handler));
+            f();
+            XSync(self.ptr, XFalse);
+            XSetErrorHandler(old_handler);
+        }
+
+        X_ERROR.load(Ordering::SeqCst)
+    }
+
+    pub fn flush(&self) {
+        unsafe { XFlush(self.ptr) };
+    }
+
     pub fn sync(&self, discard: bool) {
         let discard = if discard { XTrue } else { XFalse };
 
         unsafe { XSync(self.ptr, discard) };
     }
 
-    pub fn screen(&self, id: i32) -> Screen {
+    pub fn default_screen(&self) -> i32 {
+        unsafe { XDefaultScreen(self.ptr) }
+    }
+
+    pub fn screen_count(&self) -> i32 {
+        unsafe { XScreenCount(self.ptr) }
+    }
+
+    /// Returns the maximum request size in bytes, using BIG-REQUESTS if available
+    pub fn max_request_size(&self) -> usize {
+        let size = match unsafe { XExtendedMaxRequestSize(self.ptr) } {
+            0 => unsafe { XMaxRequestSize(self.ptr) },
+            size => size,
+        };
+
+        // The server reports the size in 4 byte units
+        size as usize * 4
+    }
+
+    pub fn screen(&self, id: i32) -> Result<Screen> {
         let ptr = unsafe { XScreenOfDisplay(self.ptr, id) };
-        assert!(!ptr.is_null());
+        if ptr.is_null() {
+            bail!("Could not get screen {}", id);
+        }
+
+        Ok(Screen { ptr })
+    }
 
-        Screen { ptr }
+    pub fn get_image(&self, drawable: u64, rect: Rect, mask: u32, format: i32) -> Result<Image> {
+        let mut ptr = std::ptr::null_mut();
+        // A rectangle outside of the drawable is an error Xlib would exit on
+        let failed = self.trap_errors(|| {
+            ptr = unsafe {
+                XGetImage(
+                    self.ptr,
+                    drawable,
+                    rect.x as i32,
+                    rect.y as i32,
+                    rect.width as u32,
+                    rect.height as u32,
+                    mask as u64,
+                    format,
+                )
+            }
+        });
+        if failed || ptr.is_null() {
+            bail!("Could not get image of drawable {}", drawable);
+        }
+
+        Ok(Image { ptr, shm: None })
     }
 
-    pub fn get_image(
+    /// Captures an image through a shared memory segment so no pixel data has to be
+    /// transferred over the connection
+    pub(crate) fn shm_get_image(
         &self,
-        drawable: u64,
-        x: i16,
-        y: i16,
-        width: i32,
-        height: i32,
+        drawable: Drawable,
+        visual: *mut Visual,
+        depth: i32,
+        rect: Rect,
         mask: u32,
-        format: i32,
-    ) -> Image {
+    ) -> Result<Image> {
+        if !self.has_shm {
+            bail!("MIT-SHM is not available");
+        }
+
+        // The image keeps a pointer to the segment info so it needs a stable address
+        let mut segment = Box::new(ShmSegment {
+            display: self.ptr,
+            info: XShmSegmentInfo {
+                shmseg: 0,
+                shmid: -1,
+                shmaddr: std::ptr::null_mut(),
+                read_only: XFalse,
+            },
+            attached: false,
+        });
+
         let ptr = unsafe {
-            XGetImage(
+            XShmCreateImage(
                 self.ptr,
-                drawable as u64,
-                x as i32,
-                y as i32,
-                width as u32,
-                height as u32,
-                mask as u64,
-                format,
+                visual,
+                depth as u32,
+                ZPixmap,
+                std::ptr::null_mut(),
+                &mut segment.info as *mut _ as *mut _,
+                rect.width as u32,
+                rect.height as u32,
             )
         };
-        assert!(!ptr.is_null());
+        if ptr.is_null() {
+            bail!("Failed to create shared memory image");
+        }
+
+        let mut image = Image {
+            ptr,
+            shm: Some(segment),
+        };
+        let size = image.bytes_per_line as usize * image.height as usize;
+        let segment = image.shm.as_mut().unwrap();
+
+        let shmid = unsafe { libc::shmget(IPC_PRIVATE, size, IPC_CREAT | 0o600) };
+        if shmid < 0 {
+            bail!("Failed to create shared memory segment");
+        }
+        segment.info.shmid = shmid;
+
+        let shmaddr = unsafe { libc::shmat(shmid, std::ptr::null(), 0) };
+        if shmaddr as isize == -1 {
+            unsafe { libc::shmctl(shmid, IPC_RMID, std::ptr::null_mut()) };
+            bail!("Failed to attach shared memory segment");
+        }
+        segment.info.shmaddr = shmaddr as *mut c_char;
+        unsafe { (*ptr).data = shmaddr as *mut c_char };
 
-        Image { ptr }
+        // Attaching fails asynchronously on remote connections
+        let failed = self.trap_errors(|| unsafe {
+            XShmAttach(self.ptr, &mut segment.info as *mut _ as *mut _);
+        });
+
+        // The segment is destroyed as soon as both sides detached
+        unsafe { libc::shmctl(shmid, IPC_RMID, std::ptr::null_mut()) };
+
+        if failed {
+            bail!("The X server could not attach the shared memory segment");
+        }
+        segment.attached = true;
+
+        // A BadMatch for areas outside of the drawable would exit through the default handler
+        let mut found = XFalse;
+        let failed = self.trap_errors(|| {
+            found = unsafe {
+                XShmGetImage(self.ptr, drawable, ptr, rect.x as i32, rect.y as i32, mask)
+            };
+        });
+        if failed || found == XFalse {
+            bail!("Failed to get image through shared memory");
+        }
+
+        Ok(image)
     }
 
-    pub fn put_image(
+    /// Draws all of `frame` into `drawable` at `x` and `y`
+    pub(crate) fn put_frame(
         &self,
         drawable: Drawable,
         gc: GC,
-        image: &mut Image,
-        src_x: i32,
-        src_y: i32,
-        dest_x: i32,
-        dest_y: i32,
-        width: u32,
-        height: u32,
-    ) {
+        visual: *mut Visual,
+        frame: &Frame,
+        x: i32,
+        y: i32,
+    ) -> Result<()> {
+        let (width, height) = (frame.width(), frame.height());
+        let ptr = unsafe {
+            XCreateImage(
+                self.ptr,
+                visual,
+                frame.depth() as u32,
+                ZPixmap,
+                0,
+                frame.data().as_ptr() as *mut c_char,
+                width,
+                height,
+                32,
+                frame.stride() as c_int,
+            )
+        };
+        if ptr.is_null() {
+            bail!(
+                "Failed to create an image with a depth of {}",
+                frame.depth()
+            );
+        }
+
+        let image = unsafe { &mut *ptr };
+        let result = if image.bits_per_pixel == frame.bits_per_pixel() {
+            // Xlib splits large images into several requests, any of them can fail. It splits
+            // into bands of rows and halves the width of rows too long for a single request
+            let failed = self.trap_errors(|| unsafe {
+                XPutImage(self.ptr, drawable, gc, ptr, 0, 0, x, y, width, height);
+            });
+            if failed {
+                Err(anyhow!(
+                    "Failed to upload the {}x{} image to {},{} of drawable {}",
+                    width,
+                    height,
+                    x,
+                    y,
+                    drawable
+                ))
+            } else {
+                Ok(())
+            }
+        } else {
+            Err(anyhow!(
+                "The display uses {} instead of {} bits per pixel for a depth of {}",
+                image.bits_per_pixel,
+                frame.bits_per_pixel(),
+                frame.depth()
+            ))
+        };
+
+        // The pixel data still belongs to the frame
+        image.data = std::ptr::null_mut();
+        unsafe { XDestroyImage(ptr) };
+
+        result
+    }
+
+    /// Repaints `rect` of `w` with its background
+    pub fn clear_area(&self, w: Window, rect: Rect) {
+        unsafe {
+            XClearArea(
+                self.ptr,
+                w,
+                rect.x as i32,
+                rect.y as i32,
+                rect.width as u32,
+                rect.height as u32,
+                XFalse,
+            )
+        };
+    }
+
+    /// Returns the position in root coordinates and the size of a viewable window
+    pub fn window_rect(&self, w: Window) -> Result<Rect> {
+        let mut attributes: XWindowAttributes = unsafe { std::mem::zeroed() };
+        if self.trap_errors(|| unsafe {
+            XGetWindowAttributes(self.ptr, w, &mut attributes);
+        }) {
+            bail!("Window {:#x} does not exist", w);
+        }
+
+        if attributes.map_state != IsViewable {
+            bail!("Window {:#x} is not mapped", w);
+        }
+
+        let (mut x, mut y, mut child) = (0, 0, 0);
+        unsafe {
+            XTranslateCoordinates(
+                self.ptr,
+                w,
+                attributes.root,
+                0,
+                0,
+                &mut x,
+                &mut y,
+                &mut child,
+            )
+        };
+
+        Ok(Rect {
+            x: x as i16,
+            y: y as i16,
+            width: attributes.width as u16,
+            height: attributes.height as u16,
+        })
+    }
+
+    /// Returns the window the window manager reports as active in `_NET_ACTIVE_WINDOW`
+    pub fn active_window(&self, root: Window) -> Result<Window> {
+        let atom = self
+            .intern_atom("_NET_ACTIVE_WINDOW", true)
+            .ok()
+            .filter(|&atom| atom != 0)
+            .context("The window manager does not set _NET_ACTIVE_WINDOW")?;
+
+        let (mut actual_type, mut actual_format) = (0, 0);
+        let (mut count, mut remaining) = (0, 0);
+        let mut data = std::ptr::null_mut();
         unsafe {
-            XPutImage(
-                self.ptr, drawable, gc, image.ptr, src_x, src_y, dest_x, dest_y, width, height,
+            XGetWindowProperty(
+                self.ptr,
+                root,
+                atom,
+                0,
+                1,
+                XFalse,
+                XA_WINDOW,
+                &mut actual_type,
+                &mut actual_format,
+                &mut count,
+                &mut remaining,
+                &mut data,
             )
         };
+
+        // 32 bit properties are stored as longs by Xlib
+        let window = (actual_type == XA_WINDOW && actual_format == 32 && count == 1)
+            .then(|| unsafe { *(data as *const Window) });
+        if !data.is_null() {
+            unsafe { x11::xlib::XFree(data as *mut c_void) };
+        }
+
+        window
+            .filter(|&window| window != 0)
+            .context("There is no active window")
     }
 
+    /// Returns the root window coordinates of the pointer
+    pub fn query_pointer(&self, w: Window) -> (i32, i32) {
+        let (mut root, mut child) = (0, 0);
+        let (mut root_x, mut root_y, mut win_x, mut win_y) = (0, 0, 0, 0);
+        let mut mask = 0;
+
+        unsafe {
+            XQueryPointer(
+                self.ptr,
+                w,
+                &mut root,
+                &mut child,
+                &mut root_x,
+                &mut root_y,
+                &mut win_x,
+                &mut win_y,
+                &mut mask,
+            )
+        };
+
+        (root_x, root_y)
+    }
+
+    /// Moves the pointer to `x`, `y` on the screen of `root`
+    pub fn warp_pointer(&self, root: Window, x: i32, y: i32) {
+        unsafe { XWarpPointer(self.ptr, 0, root, 0, 0, 0, 0, x, y) };
+    }
+
+    /// Keeps the pointer from leaving `rect` with a pointer barrier on each side
+    ///
+    /// The barriers stay up until the returned value is dropped. Needs XFixes 5.0.
+    #[cfg(feature = "cursor")]
+    pub fn confine_pointer(&self, root: Window, rect: Rect) -> Result<PointerBarriers<'_>> {
+        let (mut major, mut minor) = (0, 0);
+        let found =
+            unsafe { x11::xfixes::XFixesQueryVersion(self.ptr, &mut major, writable(&mut minor)) };
+        if found != XTrue || major < 5 {
+            bail!("Pointer barriers need XFixes 5.0");
+        }
+
+        // The pointer stops in front of barriers on the right and bottom and on top of the
+        // others, which keeps it on the pixels of the rectangle
+        let (left, top, right, bottom) =
+            (rect.x as i32, rect.y as i32, rect.right(), rect.bottom());
+        let lines = [
+            (left, top, left, bottom),
+            (right, top, right, bottom),
+            (left, top, right, top),
+            (left, bottom, right, bottom),
+        ];
+
+        let mut barriers = PointerBarriers {
+            display: self,
+            barriers: Vec::new(),
+        };
+        let failed = self.trap_errors(|| {
+            for &(x1, y1, x2, y2) in &lines {
+                // No directions and devices block all of them in both directions
+                let barrier = unsafe {
+                    x11::xfixes::XFixesCreatePointerBarrier(
+                        self.ptr,
+                        root,
+                        x1,
+                        y1,
+                        x2,
+                        y2,
+                        0,
+                        0,
+                        std::ptr::null_mut(),
+                    )
+                };
+                barriers.barriers.push(barrier);
+            }
+        });
+        if failed {
+            bail!("Failed to create pointer barriers around {}", rect);
+        }
+
+        Ok(barriers)
+    }
+
+    /// Lists the connected RandR outputs that are driven by a CRTC, or the Xinerama screens if
+    /// RandR 1.3 is missing or reports none
+    ///
+    /// libXrandr and libXinerama are loaded at runtime so nora keeps working without them as
+    /// long as no monitor information is requested.
+    pub fn monitors(&self, root: Window) -> Result<Vec<Monitor>> {
+        let randr_error = match self.randr_monitors(root) {
+            Ok(monitors) if !monitors.is_empty() => return Ok(monitors),
+            Ok(_) => anyhow!("RandR reports no monitors"),
+            Err(e) => e,
+        };
+
+        self.xinerama_monitors()
+            .with_context(|| anyhow!("{:#}, falling back to Xinerama failed", randr_error))
+    }
+
+    fn randr_monitors(&self, root: Window) -> Result<Vec<Monitor>> {
+        let monitors = self
+            .outputs(root)?
+            .into_iter()
+            .filter_map(|output| {
+                let rect = output.rect.filter(|_| output.connected)?;
+                Some(Monitor {
+                    name: output.name,
+                    rect,
+                    primary: output.primary,
+                })
+            })
+            .collect();
+
+        Ok(monitors)
+    }
+
+    /// Keeps the screensaver and DPMS from blanking the screen until the returned guard is
+    /// dropped or the connection is closed
+    ///
+    /// Needs version 1.1 of the MIT-SCREEN-SAVER extension, libXss is loaded at runtime for it.
+    pub fn suspend_screensaver(&self) -> Result<ScreensaverSuspension<'_>> {
+        let xss = Xss::open().context("Failed to load libXss")?;
+        let dpy = self.ptr as *mut x11_dl::xlib::Display;
+
+        let (mut major, mut minor) = (0, 0);
+        let found = unsafe { (xss.XScreenSaverQueryExtension)(dpy, &mut major, &mut minor) };
+        if found == XFalse {
+            bail!("The X server does not support MIT-SCREEN-SAVER");
+        }
+        if unsafe { (xss.XScreenSaverQueryVersion)(dpy, &mut major, &mut minor) } == 0 {
+            bail!("Failed to query the MIT-SCREEN-SAVER version");
+        }
+        if (major, minor) < (1, 1) {
+            bail!(
+                "MIT-SCREEN-SAVER {}.{} can not suspend the screensaver, that needs 1.1",
+                major,
+                minor
+            );
+        }
+
+        unsafe { (xss.XScreenSaverSuspend)(dpy, XTrue) };
+        self.flush();
+
+        Ok(ScreensaverSuspension { display: self, xss })
+    }
+
+    /// Restarts the idle time after which the screensaver starts, like input would
+    pub fn reset_screensaver(&self) {
+        unsafe { XResetScreenSaver(self.ptr) };
+        self.flush();
+    }
+
+    /// Xinerama has no names or primary monitor, the monitors are named after their index
+    fn xinerama_monitors(&self) -> Result<Vec<Monitor>> {
+        let xinerama = Xinerama::open().context("Failed to load libXinerama")?;
+        let dpy = self.ptr as *mut x11_dl::xlib::Display;
+
+        let (mut event_base, mut error_base) = (0, 0);
+        let found =
+            unsafe { (xinerama.XineramaQueryExtension)(dpy, &mut event_base, &mut error_base) };
+        if found == XFalse || unsafe { (xinerama.XineramaIsActive)(dpy) } == XFalse {
+            bail!("Xinerama is not active");
+        }
+
+        let mut count = 0;
+        let screens = unsafe { (xinerama.XineramaQueryScreens)(dpy, &mut count) };
+        if screens.is_null() {
+            bail!("Failed to query the Xinerama screens");
+        }
+
+        let monitors = unsafe { std::slice::from_raw_parts(screens, count as usize) }
+            .iter()
+            .enumerate()
+            .map(|(i, screen)| Monitor {
+                name: format!("xinerama-{}", i),
+                rect: Rect {
+                    x: screen.x_org,
+                    y: screen.y_org,
+                    width: screen.width as u16,
+                    height: screen.height as u16,
+                },
+                primary: false,
+            })
+            .collect();
+        unsafe { x11::xlib::XFree(screens as *mut c_void) };
+
+        Ok(monitors)
+    }
+
+    /// Returns the names of outputs that are connected but do not show any part of the screen
+    ///
+    /// Outputs of a secondary GPU end up like this until they are given a source provider.
+    pub fn unused_outputs(&self, root: Window) -> Result<Vec<String>> {
+        let names = self
+            .outputs(root)?
+            .into_iter()
+            .filter(|output| output.connected && output.rect.is_none())
+            .map(|output| output.name)
+            .collect();
+
+        Ok(names)
+    }
+
+    /// Returns how many GPUs RandR knows about
+    pub fn provider_count(&self, root: Window) -> Result<usize> {
+        let xrandr = self.xrandr()?;
+        let dpy = self.ptr as *mut x11_dl::xlib::Display;
+
+        // Providers are new in 1.4, older servers answer with BadRequest which Xlib exits on
+        let (mut major, mut minor) = (0, 0);
+        let found = unsafe { (xrandr.XRRQueryVersion)(dpy, &mut major, &mut minor) };
+        if found == 0 || (major, minor) < (1, 4) {
+            bail!("The server does not support RandR 1.4");
+        }
+
+        let resources = unsafe { (xrandr.XRRGetProviderResources)(dpy, root) };
+        if resources.is_null() {
+            bail!("Failed to get RandR provider resources");
+        }
+
+        let count = unsafe { (*resources).nproviders } as usize;
+        unsafe { (xrandr.XRRFreeProviderResources)(resources) };
+
+        Ok(count)
+    }
+
+    fn outputs(&self, root: Window) -> Result<Vec<Output>> {
+        let xrandr = self.xrandr()?;
+        let dpy = self.ptr as *mut x11_dl::xlib::Display;
+
+        // Getting the current screen resources is new in 1.3 and an error before
+        let (mut major, mut minor) = (0, 0);
+        let found = unsafe { (xrandr.XRRQueryVersion)(dpy, &mut major, &mut minor) };
+        if found == 0 || (major, minor) < (1, 3) {
+            bail!("The server does not support RandR 1.3");
+        }
+
+        let resources = unsafe { (xrandr.XRRGetScreenResourcesCurrent)(dpy, root) };
+        if resources.is_null() {
+            bail!("Failed to get RandR screen resources");
+        }
+
+        let output_ids = unsafe {
+            std::slice::from_raw_parts((*resources).outputs, (*resources).noutput as usize)
+        };
+        let primary = unsafe { (xrandr.XRRGetOutputPrimary)(dpy, root) };
+
+        let mut outputs = Vec::new();
+        for &output in output_ids {
+            let output_info = unsafe { (xrandr.XRRGetOutputInfo)(dpy, resources, output) };
+            if output_info.is_null() {
+                continue;
+            }
+
+            let info = unsafe { &*output_info };
+            let name = unsafe {
+                std::slice::from_raw_parts(info.name as *const u8, info.nameLen as usize)
+            };
+
+            let mut rect = None;
+            if info.crtc != 0 {
+                let crtc_info = unsafe { (xrandr.XRRGetCrtcInfo)(dpy, resources, info.crtc) };
+
+                if !crtc_info.is_null() {
+                    let crtc = unsafe { &*crtc_info };
+                    rect = Some(Rect {
+                        x: crtc.x as i16,
+                        y: crtc.y as i16,
+                        width: crtc.width as u16,
+                        height: crtc.height as u16,
+                    });
+
+                    unsafe { (xrandr.XRRFreeCrtcInfo)(crtc_info) };
+                }
+            }
+
+            outputs.push(Output {
+                name: String::from_utf8_lossy(name).into_owned(),
+                connected: info.connection as i32 == RR_Connected,
+                rect,
+                primary: output == primary,
+            });
+
+            unsafe { (xrandr.XRRFreeOutputInfo)(output_info) };
+        }
+
+        unsafe { (xrandr.XRRFreeScreenResources)(resources) };
+
+        Ok(outputs)
+    }
+
+    #[cfg(feature = "cursor")]
     pub fn get_cursor_image(&self) -> Result<CursorImage> {
         if !self.has_xfixes {
             bail!("xfixes version is too old");
         }
 
         let ptr = unsafe { x11::xfixes::XFixesGetCursorImage(self.ptr) };
-        assert!(!ptr.is_null());
+        if ptr.is_null() {
+            bail!("Could not get cursor image");
+        }
 
         Ok(CursorImage { ptr })
     }
 
-    pub fn free_gc(&self, gc: GC) {
+    pub(crate) fn free_gc(&self, gc: GC) {
         unsafe { XFreeGC(self.ptr, gc) };
     }
 
@@ -228,15 +1463,108 @@ impl Display {
 impl Drop for Display {
     fn drop(&mut self) {
         unsafe {
-            libc::free(self.ptr as *mut c_void);
+            XCloseDisplay(self.ptr);
         }
     }
 }
 
+pub enum Event {
+    /// Unshifted keysym of a pressed key
+    KeyPress {
+        keysym: KeySym,
+        time: Time,
+    },
+    ScreenChange {
+        width: i32,
+        height: i32,
+    },
+    /// A pointer button was pressed or released, in root coordinates
+    Button {
+        button: u32,
+        pressed: bool,
+        x: i32,
+        y: i32,
+    },
+    /// The pointer moved, in root coordinates
+    Motion {
+        x: i32,
+        y: i32,
+    },
+    /// Part of a window lost its contents, relative to the window
+    ///
+    /// The background is restored by the server but anything drawn over it is gone.
+    Expose {
+        rect: Rect,
+    },
+    /// Another client wants a selection nora owns converted to `target`
+    SelectionRequest {
+        requestor: Window,
+        selection: Atom,
+        target: Atom,
+        property: Atom,
+        time: Time,
+    },
+    /// Another client took over a selection nora owned
+    SelectionClear {
+        selection: Atom,
+    },
+    /// A property was deleted on a window whose property changes nora selected
+    PropertyDelete {
+        window: Window,
+        atom: Atom,
+    },
+}
+
+/// Returns the pixel value of the opaque color `rgb` on `visual`
+///
+/// On 32 bit visuals the bits outside of the color masks are alpha.
+pub(crate) fn opaque_pixel(visual: *mut Visual, depth: i32, rgb: [u8; 3]) -> c_ulong {
+    let visual = unsafe { &*visual };
+    let masks = [visual.red_mask, visual.green_mask, visual.blue_mask];
+
+    let mut pixel = 0;
+    for (&channel, &mask) in rgb.iter().zip(masks.iter()) {
+        if mask == 0 {
+            continue;
+        }
+
+        // Keep the high bits of channels with less than 8 bits
+        let bits = mask.count_ones().min(8);
+        let value = (channel as c_ulong >> (8 - bits)) << mask.trailing_zeros();
+        pixel |= value & mask;
+    }
+
+    let color = masks.iter().fold(0, |color, mask| color | mask);
+    let alpha = if depth == 32 { !color & 0xffff_ffff } else { 0 };
+    pixel | alpha
+}
+
+/// An X extension and the version the server supports, `None` if it is missing
+pub struct Extension {
+    pub name: &'static str,
+    pub version: Option<(i32, i32)>,
+}
+
+// A RandR output, `rect` is the area of its CRTC if it has one
+struct Output {
+    name: String,
+    connected: bool,
+    rect: Option<Rect>,
+    primary: bool,
+}
+
+pub struct Monitor {
+    pub name: String,
+    pub rect: Rect,
+    pub primary: bool,
+}
+
+#[cfg(feature = "cursor")]
 pub struct CursorImage {
     ptr: *mut XFixesCursorImage,
 }
 
+#[cfg(feature = "cursor")]
 impl CursorImage {
     pub fn x(&self) -> i16 {
         unsafe { &*self.ptr }.x
@@ -263,35 +1591,124 @@ impl CursorImage {
     }
 
     pub fn pixels(&self) -> &[CPixel] {
-        let size = self.width() * self.height();
+        let size = self.width() as usize * self.height() as usize;
         let ptr = unsafe { &*self.ptr }.pixels;
-        assert!(!ptr.is_null());
+        if ptr.is_null() {
+            return &[];
+        }
 
-        unsafe { std::slice::from_raw_parts(ptr as *mut _, size as usize) }
+        unsafe { std::slice::from_raw_parts(ptr as *mut _, size) }
     }
 }
 
+#[cfg(feature = "cursor")]
 impl Drop for CursorImage {
     fn drop(&mut self) {
         unsafe {
-            libc::free(self.ptr as *mut c_void);
+            x11::xlib::XFree(self.ptr as *mut c_void);
         }
     }
 }
 
-#[repr(C)]
-pub struct CPixel {
-    pub r: u8,
-    pub g: u8,
-    pub b: u8,
-    pub a: u8,
-    padding: [u8; 4],
+/// The screensaver suspension from `Display::suspend_screensaver`, lifted when dropped
+pub struct ScreensaverSuspension<'a> {
+    display: &'a Display,
+    xss: Xss,
+}
+
+impl Drop for ScreensaverSuspension<'_> {
+    fn drop(&mut self) {
+        let dpy = self.display.ptr as *mut x11_dl::xlib::Display;
+        unsafe { (self.xss.XScreenSaverSuspend)(dpy, XFalse) };
+        self.display.flush();
+    }
+}
+
+/// Pointer barriers from `Display::confine_pointer`, destroyed when dropped
+#[cfg(feature = "cursor")]
+pub struct PointerBarriers<'a> {
+    display: &'a Display,
+    barriers: Vec<x11::xfixes::PointerBarrier>,
 }
 
+#[cfg(feature = "cursor")]
+impl Drop for PointerBarriers<'_> {
+    fn drop(&mut self) {
+        let display = self.display;
+        // Barriers that failed to be created are not valid and fail to be destroyed
+        display.trap_errors(|| {
+            for &barrier in &self.barriers {
+                unsafe { x11::xfixes::XFixesDestroyPointerBarrier(display.ptr, barrier) };
+            }
+        });
+    }
+}
+
+/// A cursor pixel, XFixes stores them as premultiplied ARGB in the low 32 bits of a long
+#[cfg(feature = "cursor")]
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub struct CPixel(c_ulong);
+
+#[cfg(feature = "cursor")]
+impl CPixel {
+    pub fn a(self) -> u8 {
+        (self.0 >> 24) as u8
+    }
+
+    pub fn r(self) -> u8 {
+        (self.0 >> 16) as u8
+    }
+
+    pub fn g(self) -> u8 {
+        (self.0 >> 8) as u8
+    }
+
+    pub fn b(self) -> u8 {
+        self.0 as u8
+    }
+
+    /// Returns the pixel as RGBA with the colors no longer multiplied by the alpha, which is how
+    /// image files store them
+    pub fn to_rgba(self) -> [u8; 4] {
+        let a = self.a() as u32;
+        let straight = |channel: u8| match a {
+            0 => 0,
+            _ => ((channel as u32 * 255 + a / 2) / a).min(255) as u8,
+        };
+
+        [
+            straight(self.r()),
+            straight(self.g()),
+            straight(self.b()),
+            self.a(),
+        ]
+    }
+}
+
+#[cfg(all(test, feature = "cursor"))]
+impl CPixel {
+    pub(crate) fn from_argb(argb: u32) -> Self {
+        CPixel(argb as c_ulong)
+    }
+}
+
+/// A screen owned by the `Display` it was returned from
 pub struct Screen {
     ptr: *mut x11::xlib::Screen,
 }
 
+impl Screen {
+    pub fn rect(&self) -> Rect {
+        Rect {
+            x: 0,
+            y: 0,
+            width: self.width as u16,
+            height: self.height as u16,
+        }
+    }
+}
+
 impl Deref for Screen {
     type Target = x11::xlib::Screen;
 
@@ -300,16 +1717,16 @@ impl Deref for Screen {
     }
 }
 
-impl Drop for Screen {
-    fn drop(&mut self) {
-        unsafe {
-            libc::free(self.ptr as *mut c_void);
-        }
-    }
-}
-
 pub struct Image {
     ptr: *mut XImage,
+    shm: Option<Box<ShmSegment>>,
+}
+
+impl Image {
+    pub fn data(&self) -> &[u8] {
+        let len = self.bytes_per_line as usize * self.height as usize;
+        unsafe { std::slice::from_raw_parts(self.data as *const u8, len) }
+    }
 }
 
 impl Deref for Image {
@@ -322,8 +1739,86 @@ impl Deref for Image {
 
 impl Drop for Image {
     fn drop(&mut self) {
+        if let Some(segment) = &mut self.shm {
+            unsafe {
+                if segment.attached {
+                    XShmDetach(segment.display, &mut segment.info as *mut _ as *mut _);
+                }
+                if !segment.info.shmaddr.is_null() {
+                    libc::shmdt(segment.info.shmaddr as *const c_void);
+                }
+
+                // The pixel data belongs to the segment
+                (*self.ptr).data = std::ptr::null_mut();
+            }
+        }
+
+        // Also frees the pixel data unless it belongs to a shared memory segment
         unsafe {
-            libc::free(self.ptr as *mut c_void);
+            XDestroyImage(self.ptr);
         }
     }
 }
+
+// x11::xshm::XshmSegmentInfo does not expose its fields
+#[repr(C)]
+struct XShmSegmentInfo {
+    shmseg: x11::xshm::ShmSeg,
+    shmid: c_int,
+    shmaddr: *mut c_char,
+    read_only: x11::xlib::Bool,
+}
+
+struct ShmSegment {
+    display: *mut x11::xlib::_XDisplay,
+    info: XShmSegmentInfo,
+    attached: bool,
+}
+
+unsafe extern "C" fn error_handler(
+    _display: *mut x11::xlib::Display,
+    _event: *mut XErrorEvent,
+) -> c_int {
+    X_ERROR.store(true, Ordering::SeqCst);
+    0
+}
+
+#[cfg(all(test, feature = "cursor"))]
+mod tests {
+    use super::CPixel;
+
+    #[test]
+    fn channels_come_from_argb() {
+        let pixel = CPixel::from_argb(0x8040_2010);
+        assert_eq!(
+            [pixel.a(), pixel.r(), pixel.g(), pixel.b()],
+            [0x80, 0x40, 0x20, 0x10]
+        );
+    }
+
+    #[test]
+    fn to_rgba_unpremultiplies() {
+        assert_eq!(CPixel::from_argb(0x8040_2010).to_rgba(), [128, 64, 32, 128]);
+        assert_eq!(
+            CPixel::from_argb(0xffab_cdef).to_rgba(),
+            [0xab, 0xcd, 0xef, 0xff]
+        );
+    }
+
+    #[test]
+    fn to_rgba_handles_transparent_and_invalid_pixels() {
+        assert_eq!(CPixel::from_argb(0).to_rgba(), [0, 0, 0, 0]);
+        // Colors brighter than the alpha allows are clamped instead of wrapping
+        assert_eq!(CPixel::from_argb(0x10ff_0000).to_rgba(), [255, 0, 0, 16]);
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn channels_ignore_the_upper_bits_of_the_long() {
+        let pixel = CPixel((0xdead_beef << 32) | 0xff10_2030);
+        assert_eq!(
+            [pixel.a(), pixel.r(), pixel.g(), pixel.b()],
+            [0xff, 0x10, 0x20, 0x30]
+        );
+    }
+}
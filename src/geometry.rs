@@ -0,0 +1,236 @@
+use std::fmt;
+use std::num::ParseIntError;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context, Error, Result};
+
+/// A rectangle in X coordinates, written as `WxH+X+Y` like X geometries
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rect {
+    pub x: i16,
+    pub y: i16,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl Rect {
+    /// Returns the first column right of the rectangle
+    pub fn right(&self) -> i32 {
+        self.x as i32 + self.width as i32
+    }
+
+    /// Returns the first row below the rectangle
+    pub fn bottom(&self) -> i32 {
+        self.y as i32 + self.height as i32
+    }
+
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        x >= self.x as i32 && y >= self.y as i32 && x < self.right() && y < self.bottom()
+    }
+
+    /// Returns the area covered by both rectangles, if there is any
+    pub fn intersect(&self, other: Rect) -> Option<Rect> {
+        let left = self.x.max(other.x);
+        let top = self.y.max(other.y);
+        let right = self.right().min(other.right());
+        let bottom = self.bottom().min(other.bottom());
+
+        if right <= left as i32 || bottom <= top as i32 {
+            return None;
+        }
+
+        Some(Rect {
+            x: left,
+            y: top,
+            width: (right - left as i32) as u16,
+            height: (bottom - top as i32) as u16,
+        })
+    }
+
+    /// Returns the rectangle spanned by two pixels, both of which are inside of it
+    pub fn from_corners((x1, y1): (i32, i32), (x2, y2): (i32, i32)) -> Rect {
+        Rect {
+            x: x1.min(x2) as i16,
+            y: y1.min(y2) as i16,
+            width: ((x1 - x2).abs() + 1) as u16,
+            height: ((y1 - y2).abs() + 1) as u16,
+        }
+    }
+
+    /// Returns the strips along the top, bottom, left and right edge that are `thickness` wide
+    ///
+    /// They overlap in the corners and are narrower if the rectangle is.
+    pub fn edges(&self, thickness: u16) -> [Rect; 4] {
+        let horizontal = thickness.min(self.height);
+        let vertical = thickness.min(self.width);
+
+        [
+            Rect {
+                height: horizontal,
+                ..*self
+            },
+            Rect {
+                y: (self.bottom() - horizontal as i32) as i16,
+                height: horizontal,
+                ..*self
+            },
+            Rect {
+                width: vertical,
+                ..*self
+            },
+            Rect {
+                x: (self.right() - vertical as i32) as i16,
+                width: vertical,
+                ..*self
+            },
+        ]
+    }
+
+    /// Returns where a window with a border of `border` pixels has to be placed to keep its
+    /// inside at the rectangle, X positions windows by the outer corner of their border
+    pub fn border_origin(&self, border: u16) -> (i32, i32) {
+        (self.x as i32 - border as i32, self.y as i32 - border as i32)
+    }
+
+    /// Moves the rectangle inside of `bounds`, shrinking it only if it is larger
+    pub fn clamp_to(&self, bounds: Rect) -> Rect {
+        let width = self.width.min(bounds.width);
+        let height = self.height.min(bounds.height);
+        let x = (self.x as i32).clamp(bounds.x as i32, bounds.right() - width as i32);
+        let y = (self.y as i32).clamp(bounds.y as i32, bounds.bottom() - height as i32);
+
+        Rect {
+            x: x as i16,
+            y: y as i16,
+            width,
+            height,
+        }
+    }
+}
+
+impl FromStr for Rect {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.is_empty() {
+            bail!("expected WxH+X+Y");
+        }
+
+        // The offset is optional, the size is not
+        let (size, offset) = s.split_once('+').unwrap_or((s, "0+0"));
+        if size.is_empty() {
+            bail!("missing size before the offset, expected WxH+X+Y");
+        }
+        let (width, height) = size
+            .split_once('x')
+            .with_context(|| anyhow!("missing x between width and height in {:?}", size))?;
+        let (x, y) = offset
+            .split_once('+')
+            .context("missing y offset, expected both as +X+Y")?;
+
+        // Checked in the order they are written
+        let (width, height) = (parse_part(width, "width")?, parse_part(height, "height")?);
+        let rect = Rect {
+            x: parse_part(x, "x offset")?,
+            y: parse_part(y, "y offset")?,
+            width,
+            height,
+        };
+
+        if rect.width == 0 || rect.height == 0 {
+            bail!("width and height have to be larger than 0");
+        }
+
+        Ok(rect)
+    }
+}
+
+/// Parses one of the numbers of a geometry, `name` says which one in errors
+fn parse_part<T>(value: &str, name: &str) -> Result<T>
+where
+    T: FromStr<Err = ParseIntError>,
+{
+    if value.is_empty() {
+        bail!("missing {}", name);
+    }
+
+    value
+        .parse()
+        // Only the outermost message of an error is shown for arguments
+        .map_err(|e| anyhow!("invalid {} {:?}, {}", name, value, e))
+}
+
+impl fmt::Display for Rect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}x{}+{}+{}", self.width, self.height, self.x, self.y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Rect;
+
+    fn error(src: &str) -> String {
+        src.parse::<Rect>().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn parses_size_and_offset() {
+        let rect: Rect = "200x100+10+20".parse().unwrap();
+        assert_eq!(
+            rect,
+            Rect {
+                x: 10,
+                y: 20,
+                width: 200,
+                height: 100,
+            }
+        );
+        assert_eq!("200x100".parse::<Rect>().unwrap().x, 0);
+    }
+
+    #[test]
+    fn names_the_missing_part() {
+        assert_eq!(error(""), "expected WxH+X+Y");
+        assert_eq!(
+            error("+10+20"),
+            "missing size before the offset, expected WxH+X+Y"
+        );
+        assert_eq!(
+            error("200+10+20"),
+            "missing x between width and height in \"200\""
+        );
+        assert_eq!(
+            error("200x100+10"),
+            "missing y offset, expected both as +X+Y"
+        );
+        assert_eq!(error("x100"), "missing width");
+        assert_eq!(error("200x"), "missing height");
+        assert_eq!(error("200x100++20"), "missing x offset");
+        assert_eq!(error("200x100+10+"), "missing y offset");
+    }
+
+    #[test]
+    fn names_the_invalid_part() {
+        assert_eq!(
+            error("2a0x100"),
+            "invalid width \"2a0\", invalid digit found in string"
+        );
+        assert_eq!(
+            error("200x100+10+99999"),
+            "invalid y offset \"99999\", number too large to fit in target type"
+        );
+        assert_eq!(error("0x100"), "width and height have to be larger than 0");
+    }
+
+    #[test]
+    fn border_origin_is_outside_of_the_rectangle() {
+        let rect: Rect = "200x100+10+20".parse().unwrap();
+        assert_eq!(rect.border_origin(0), (10, 20));
+        assert_eq!(rect.border_origin(3), (7, 17));
+        assert_eq!(
+            "200x100".parse::<Rect>().unwrap().border_origin(5),
+            (-5, -5)
+        );
+    }
+}
@@ -0,0 +1,1168 @@
+use std::io::Write;
+use std::str::FromStr;
+
+use anyhow::{bail, Error, Result};
+
+use crate::geometry::Rect;
+
+/// Returns the size of a pixel for the Z-pixmap formats nora can process
+///
+/// Those are 24 and 32 bit depths with 8 bits per channel, see `PixelFormat` for their order.
+pub fn bytes_per_pixel(depth: i32, bits_per_pixel: i32) -> Result<usize> {
+    match (depth, bits_per_pixel) {
+        (24, 24) => Ok(3),
+        (24, 32) | (32, 32) => Ok(4),
+        (30, _) => bail!("10 bit colors of a depth of 30 are not supported"),
+        _ => bail!(
+            "Unsupported image format with a depth of {} and {} bits per pixel",
+            depth,
+            bits_per_pixel
+        ),
+    }
+}
+
+/// Order of the color channel bytes in a pixel, padding or alpha comes last with 4 bytes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PixelFormat {
+    Rgbx,
+    Bgrx,
+}
+
+impl PixelFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "rgbx" => Some(PixelFormat::Rgbx),
+            "bgrx" => Some(PixelFormat::Bgrx),
+            _ => None,
+        }
+    }
+
+    /// Works out the format from the channel masks and byte order of a Z-pixmap
+    ///
+    /// Returns `None` for layouts where red and blue are not the outer bytes.
+    pub fn detect(
+        red_mask: u64,
+        blue_mask: u64,
+        bits_per_pixel: i32,
+        msb_first: bool,
+    ) -> Option<Self> {
+        let bytes_per_pixel = bits_per_pixel as usize / 8;
+        // Index of the byte a channel mask covers, counted from the first byte in memory
+        let byte = |mask: u64| {
+            let index = (0..bytes_per_pixel).find(|i| mask == 0xff << (i * 8))?;
+            Some(if msb_first {
+                bytes_per_pixel - 1 - index
+            } else {
+                index
+            })
+        };
+
+        match (byte(red_mask)?, byte(blue_mask)?) {
+            (0, 2) => Some(PixelFormat::Rgbx),
+            (2, 0) => Some(PixelFormat::Bgrx),
+            _ => None,
+        }
+    }
+
+    /// Reorders RGB channels into the order of the format, or the other way around
+    pub fn swizzle(self, channels: [u8; 3]) -> [u8; 3] {
+        let [a, b, c] = channels;
+        match self {
+            PixelFormat::Rgbx => [a, b, c],
+            PixelFormat::Bgrx => [c, b, a],
+        }
+    }
+}
+
+/// Order of the bytes in pixels nora writes out, independent of the captured `PixelFormat`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ByteOrder {
+    Rgba,
+    Bgra,
+    Argb,
+    Abgr,
+    Rgb,
+    Bgr,
+}
+
+impl ByteOrder {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "rgba" => Some(ByteOrder::Rgba),
+            "bgra" => Some(ByteOrder::Bgra),
+            "argb" => Some(ByteOrder::Argb),
+            "abgr" => Some(ByteOrder::Abgr),
+            "rgb" => Some(ByteOrder::Rgb),
+            "bgr" => Some(ByteOrder::Bgr),
+            _ => None,
+        }
+    }
+
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            ByteOrder::Rgb | ByteOrder::Bgr => 3,
+            _ => 4,
+        }
+    }
+
+    /// Arranges a straight RGBA pixel in this order, only the first `bytes_per_pixel` are used
+    fn swizzle(self, rgba: [u8; 4]) -> [u8; 4] {
+        let [r, g, b, a] = rgba;
+        match self {
+            ByteOrder::Rgba => [r, g, b, a],
+            ByteOrder::Bgra => [b, g, r, a],
+            ByteOrder::Argb => [a, r, g, b],
+            ByteOrder::Abgr => [a, b, g, r],
+            ByteOrder::Rgb => [r, g, b, a],
+            ByteOrder::Bgr => [b, g, r, a],
+        }
+    }
+}
+
+/// A color blended over the image, given as `RRGGBBAA`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tint {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+impl FromStr for Tint {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let hex = s.strip_prefix('#').unwrap_or(s);
+        if hex.len() != 8 || !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+            bail!("expected RRGGBBAA in hex");
+        }
+
+        let channel = |i: usize| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16);
+        Ok(Tint {
+            r: channel(0)?,
+            g: channel(1)?,
+            b: channel(2)?,
+            a: channel(3)?,
+        })
+    }
+}
+
+/// Captured pixels that do not depend on the X connection anymore
+///
+/// The data is a Z-pixmap whose rows are `stride` bytes apart.
+pub struct Frame {
+    data: Vec<u8>,
+    region: Rect,
+    stride: usize,
+    depth: i32,
+    bits_per_pixel: i32,
+    pixel_format: PixelFormat,
+}
+
+impl Frame {
+    /// The pixels are taken to be in BGR(X) order, the most common one, until told otherwise
+    pub fn new(
+        data: Vec<u8>,
+        region: Rect,
+        stride: usize,
+        depth: i32,
+        bits_per_pixel: i32,
+    ) -> Result<Self> {
+        // Rows are walked by the stride, which is 0 for empty images
+        if region.width == 0 || region.height == 0 {
+            bail!("A {}x{} image has no pixels", region.width, region.height);
+        }
+
+        let row_bits = region.width as usize * bits_per_pixel as usize;
+        if stride * 8 < row_bits || data.len() < stride * region.height as usize {
+            bail!(
+                "{} bytes with a stride of {} are too few for a {}x{} image",
+                data.len(),
+                stride,
+                region.width,
+                region.height
+            );
+        }
+
+        Ok(Frame {
+            data,
+            region,
+            stride,
+            depth,
+            bits_per_pixel,
+            pixel_format: PixelFormat::Bgrx,
+        })
+    }
+
+    /// Where on the screen the frame was captured
+    pub fn region(&self) -> Rect {
+        self.region
+    }
+
+    pub fn width(&self) -> u32 {
+        self.region.width as u32
+    }
+
+    pub fn height(&self) -> u32 {
+        self.region.height as u32
+    }
+
+    /// Rows can be padded so they have to be addressed through the stride
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    pub fn depth(&self) -> i32 {
+        self.depth
+    }
+
+    pub fn bits_per_pixel(&self) -> i32 {
+        self.bits_per_pixel
+    }
+
+    /// Reinterprets the pixels with another depth, only possible between 24 and 32 bit
+    ///
+    /// Both use 4 byte pixels and the fourth byte is made opaque for a depth of 32.
+    pub fn set_depth(&mut self, depth: i32) -> Result<()> {
+        if depth == self.depth {
+            return Ok(());
+        }
+
+        let convertible = |depth| bytes_per_pixel(depth, self.bits_per_pixel).ok() == Some(4);
+        if !convertible(self.depth) || !convertible(depth) {
+            bail!(
+                "Can not convert a depth of {} with {} bits per pixel to a depth of {}",
+                self.depth,
+                self.bits_per_pixel,
+                depth
+            );
+        }
+
+        self.depth = depth;
+        if depth == 32 {
+            self.make_opaque();
+        }
+
+        Ok(())
+    }
+
+    /// Sets the alpha of every pixel to 0xff if the frame has a depth of 32
+    ///
+    /// Compositors blend windows with ARGB visuals by that byte, so anything else would let the
+    /// windows below show through the freeze.
+    pub fn make_opaque(&mut self) {
+        if self.depth != 32 || self.bits_per_pixel != 32 {
+            return;
+        }
+
+        let row_len = self.width() as usize * 4;
+        let height = self.height() as usize;
+        for row in self.data.chunks_mut(self.stride).take(height) {
+            for pixel in row[..row_len].chunks_exact_mut(4) {
+                pixel[3] = 0xff;
+            }
+        }
+    }
+
+    pub fn pixel_format(&self) -> PixelFormat {
+        self.pixel_format
+    }
+
+    /// Changes how the channels are interpreted without touching the pixels
+    pub fn set_pixel_format(&mut self, pixel_format: PixelFormat) {
+        self.pixel_format = pixel_format;
+    }
+
+    /// Size of a pixel, errors for formats the image processing does not support
+    pub fn bytes_per_pixel(&self) -> Result<usize> {
+        bytes_per_pixel(self.depth, self.bits_per_pixel)
+    }
+
+    /// The order the pixels are stored in, padding counts as alpha
+    pub fn byte_order(&self) -> Result<ByteOrder> {
+        Ok(match (self.pixel_format, self.bytes_per_pixel()?) {
+            (PixelFormat::Rgbx, 3) => ByteOrder::Rgb,
+            (PixelFormat::Rgbx, _) => ByteOrder::Rgba,
+            (PixelFormat::Bgrx, 3) => ByteOrder::Bgr,
+            (PixelFormat::Bgrx, _) => ByteOrder::Bgra,
+        })
+    }
+
+    /// Copies the pixels into tightly packed rows in `order`
+    ///
+    /// The alpha is always 0xff, frames with a depth of 32 are opaque and the others have
+    /// padding in its place.
+    pub fn to_bytes(&self, order: ByteOrder) -> Result<Vec<u8>> {
+        let bytes_per_pixel = self.bytes_per_pixel()?;
+        let (width, height) = (self.width() as usize, self.height() as usize);
+        let size = order.bytes_per_pixel();
+
+        let mut bytes = Vec::with_capacity(width * height * size);
+        for row in self.data.chunks(self.stride).take(height) {
+            for pixel in row[..width * bytes_per_pixel].chunks_exact(bytes_per_pixel) {
+                let [r, g, b] = self.pixel_format.swizzle([pixel[0], pixel[1], pixel[2]]);
+                bytes.extend_from_slice(&order.swizzle([r, g, b, 0xff])[..size]);
+            }
+        }
+
+        Ok(bytes)
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    /// Copies the part of the frame inside of `rect`, which is given in screen coordinates
+    pub fn crop(&self, rect: Rect) -> Result<Frame> {
+        if self.bits_per_pixel % 8 != 0 {
+            bail!(
+                "Can not crop images with {} bits per pixel",
+                self.bits_per_pixel
+            );
+        }
+        let bytes_per_pixel = self.bits_per_pixel as usize / 8;
+
+        let region = match self.region.intersect(rect) {
+            Some(region) => region,
+            None => bail!("{} is outside of the {} frame", rect, self.region),
+        };
+
+        let x = (region.x - self.region.x) as usize;
+        let y = (region.y - self.region.y) as usize;
+        let row_len = region.width as usize * bytes_per_pixel;
+
+        let mut data = Vec::with_capacity(row_len * region.height as usize);
+        for row in self
+            .data
+            .chunks(self.stride)
+            .skip(y)
+            .take(region.height as usize)
+        {
+            let start = x * bytes_per_pixel;
+            data.extend_from_slice(&row[start..start + row_len]);
+        }
+
+        Ok(Frame {
+            pixel_format: self.pixel_format,
+            ..Frame::new(data, region, row_len, self.depth, self.bits_per_pixel)?
+        })
+    }
+
+    /// Scales the pixels around `(x, y)` up by `zoom` to fill `target`
+    ///
+    /// The point is given in screen coordinates and the returned frame covers `target`.
+    pub fn magnify(&self, x: i32, y: i32, zoom: u16, target: Rect) -> Result<Frame> {
+        if self.bits_per_pixel % 8 != 0 {
+            bail!(
+                "Can not magnify images with {} bits per pixel",
+                self.bits_per_pixel
+            );
+        }
+        let bytes_per_pixel = self.bits_per_pixel as usize / 8;
+
+        let zoom = zoom.max(1);
+        let source = Rect {
+            x: (x - (target.width / zoom / 2) as i32) as i16,
+            y: (y - (target.height / zoom / 2) as i32) as i16,
+            width: (target.width / zoom).max(1),
+            height: (target.height / zoom).max(1),
+        }
+        .clamp_to(self.region);
+
+        let stride = target.width as usize * bytes_per_pixel;
+        let mut data = vec![0; stride * target.height as usize];
+        for (ty, row) in data.chunks_exact_mut(stride).enumerate() {
+            let sy = (source.y - self.region.y) as usize
+                + (ty / zoom as usize).min(source.height as usize - 1);
+            let src_row = &self.data[sy * self.stride..];
+
+            for (tx, pixel) in row.chunks_exact_mut(bytes_per_pixel).enumerate() {
+                let sx = (source.x - self.region.x) as usize
+                    + (tx / zoom as usize).min(source.width as usize - 1);
+                let start = sx * bytes_per_pixel;
+                pixel.copy_from_slice(&src_row[start..start + bytes_per_pixel]);
+            }
+        }
+
+        Ok(Frame {
+            pixel_format: self.pixel_format,
+            ..Frame::new(data, target, stride, self.depth, self.bits_per_pixel)?
+        })
+    }
+
+    /// Returns a copy shrunk by `factor` between 0 and 1 that averages the pixels each new one
+    /// covers
+    ///
+    /// The region keeps its position, only its size changes.
+    pub fn downscale(&self, factor: f32) -> Result<Frame> {
+        let bytes_per_pixel = self.bytes_per_pixel()?;
+        let (width, height) = (self.width() as usize, self.height() as usize);
+        let scaled = |size: usize| ((size as f32 * factor).round() as usize).clamp(1, size);
+        let target = Rect {
+            width: scaled(width) as u16,
+            height: scaled(height) as u16,
+            ..self.region
+        };
+        let (target_width, target_height) = (target.width as usize, target.height as usize);
+
+        let stride = target_width * bytes_per_pixel;
+        let mut data = vec![0; stride * target_height];
+        let mut sums = vec![0u32; bytes_per_pixel];
+        for (ty, row) in data.chunks_exact_mut(stride).enumerate() {
+            // Every source row and column ends up in exactly one box
+            let rows = ty * height / target_height..(ty + 1) * height / target_height;
+
+            for (tx, pixel) in row.chunks_exact_mut(bytes_per_pixel).enumerate() {
+                let columns = tx * width / target_width..(tx + 1) * width / target_width;
+
+                sums.iter_mut().for_each(|sum| *sum = 0);
+                for sy in rows.clone() {
+                    let src_row = &self.data[sy * self.stride..];
+                    for sx in columns.clone() {
+                        let src = &src_row[sx * bytes_per_pixel..(sx + 1) * bytes_per_pixel];
+                        for (sum, &byte) in sums.iter_mut().zip(src) {
+                            *sum += byte as u32;
+                        }
+                    }
+                }
+
+                let count = (rows.len() * columns.len()) as u32;
+                for (byte, sum) in pixel.iter_mut().zip(&sums) {
+                    *byte = ((sum + count / 2) / count) as u8;
+                }
+            }
+        }
+
+        Ok(Frame {
+            pixel_format: self.pixel_format,
+            ..Frame::new(data, target, stride, self.depth, self.bits_per_pixel)?
+        })
+    }
+
+    /// Multiplies the color channels of every pixel by `factor`, then blends `tint` over them
+    pub fn dim(&mut self, factor: f32, tint: Option<Tint>) -> Result<()> {
+        let bytes_per_pixel = self.bytes_per_pixel()?;
+        let (width, height) = (self.width(), self.height());
+        let tint = tint.map(|tint| (self.pixel_format.swizzle([tint.r, tint.g, tint.b]), tint.a));
+        dim(
+            &mut self.data,
+            width,
+            height,
+            self.stride,
+            bytes_per_pixel,
+            factor,
+            tint,
+        );
+        Ok(())
+    }
+
+    /// Replaces the colors with their luminance using the Rec. 709 weights
+    pub fn grayscale(&mut self) -> Result<()> {
+        let bytes_per_pixel = self.bytes_per_pixel()?;
+        let (width, height) = (self.width(), self.height());
+        // Out of 256 so the sum can be shifted back down
+        let weights = self.pixel_format.swizzle([54, 183, 19]);
+        grayscale(
+            &mut self.data,
+            width,
+            height,
+            self.stride,
+            bytes_per_pixel,
+            weights,
+        );
+        Ok(())
+    }
+
+    /// Approximates a gaussian blur with three box blurs in each direction
+    pub fn blur(&mut self, radius: u32) -> Result<()> {
+        let bytes_per_pixel = self.bytes_per_pixel()?;
+        let (width, height) = (self.width(), self.height());
+        blur(
+            &mut self.data,
+            width,
+            height,
+            self.stride,
+            bytes_per_pixel,
+            radius,
+        );
+        Ok(())
+    }
+
+    /// Encodes the frame as `format` and writes it to `dst`
+    pub fn encode(&self, format: Format, dst: impl Write) -> Result<()> {
+        let rgb = self.to_bytes(ByteOrder::Rgb)?;
+        encode(&rgb, self.width(), self.height(), format, dst)
+    }
+
+    /// Encodes the frame as a PNG with the channels stored in `order`
+    ///
+    /// Readers take them as RGB or RGBA, other orders swap the colors when viewed.
+    pub fn encode_png(&self, order: ByteOrder, dst: impl Write) -> Result<()> {
+        let pixels = self.to_bytes(order)?;
+        let color = match order.bytes_per_pixel() {
+            3 => png::ColorType::Rgb,
+            _ => png::ColorType::Rgba,
+        };
+
+        let mut encoder = png::Encoder::new(dst, self.width(), self.height());
+        encoder.set_color(color);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&pixels)?;
+        writer.finish()?;
+
+        Ok(())
+    }
+}
+
+/// `weights` are in the order of the pixels and add up to 256
+fn grayscale(
+    data: &mut [u8],
+    width: u32,
+    height: u32,
+    stride: usize,
+    bytes_per_pixel: usize,
+    weights: [u8; 3],
+) {
+    for row in data.chunks_mut(stride).take(height as usize) {
+        for pixel in row[..width as usize * bytes_per_pixel].chunks_exact_mut(bytes_per_pixel) {
+            let sum: u32 = pixel[..3]
+                .iter()
+                .zip(weights)
+                .map(|(&channel, weight)| channel as u32 * weight as u32)
+                .sum();
+            let luminance = ((sum + 128) >> 8).min(255) as u8;
+            pixel[..3].fill(luminance);
+        }
+    }
+}
+
+fn dim(
+    data: &mut [u8],
+    width: u32,
+    height: u32,
+    stride: usize,
+    bytes_per_pixel: usize,
+    factor: f32,
+    tint: Option<([u8; 3], u8)>,
+) {
+    let tint = tint.map(|(color, alpha)| {
+        let alpha = alpha as u32;
+        // Premultiplied in the order of the pixels, plus half of 255 for rounding
+        let color = color.map(|c| c as u32 * alpha + 127);
+        (color, 255 - alpha)
+    });
+
+    for row in data.chunks_mut(stride).take(height as usize) {
+        for pixel in row[..width as usize * bytes_per_pixel].chunks_exact_mut(bytes_per_pixel) {
+            for (i, channel) in pixel[..3].iter_mut().enumerate() {
+                *channel = (*channel as f32 * factor) as u8;
+
+                if let Some((color, inverse_alpha)) = tint {
+                    *channel = ((*channel as u32 * inverse_alpha + color[i]) / 255) as u8;
+                }
+            }
+        }
+    }
+}
+
+fn blur(
+    data: &mut [u8],
+    width: u32,
+    height: u32,
+    stride: usize,
+    bytes_per_pixel: usize,
+    radius: u32,
+) {
+    let (width, height) = (width as usize, height as usize);
+    // Larger radii only average the same clamped edge pixels
+    let radius = (radius as usize).min(width.max(height));
+    if radius == 0 {
+        return;
+    }
+
+    let offset = |x: usize, y: usize| y * stride + x * bytes_per_pixel;
+    let mut src = vec![[0; 3]; width.max(height)];
+    let mut dst = src.clone();
+
+    for _ in 0..3 {
+        for y in 0..height {
+            for (x, pixel) in src[..width].iter_mut().enumerate() {
+                let start = offset(x, y);
+                pixel.copy_from_slice(&data[start..start + 3]);
+            }
+            box_blur_line(&src[..width], &mut dst[..width], radius);
+            for (x, pixel) in dst[..width].iter().enumerate() {
+                let start = offset(x, y);
+                data[start..start + 3].copy_from_slice(pixel);
+            }
+        }
+
+        for x in 0..width {
+            for (y, pixel) in src[..height].iter_mut().enumerate() {
+                let start = offset(x, y);
+                pixel.copy_from_slice(&data[start..start + 3]);
+            }
+            box_blur_line(&src[..height], &mut dst[..height], radius);
+            for (y, pixel) in dst[..height].iter().enumerate() {
+                let start = offset(x, y);
+                data[start..start + 3].copy_from_slice(pixel);
+            }
+        }
+    }
+}
+
+/// Averages every pixel with its `radius` neighbours on each side, clamping at the edges
+fn box_blur_line(src: &[[u8; 3]], dst: &mut [[u8; 3]], radius: usize) {
+    let last = src.len() - 1;
+    let window = 2 * radius as u32 + 1;
+
+    let mut sum = [0u32; 3];
+    for i in 0..=2 * radius {
+        let pixel = src[i.saturating_sub(radius).min(last)];
+        for c in 0..3 {
+            sum[c] += pixel[c] as u32;
+        }
+    }
+
+    for x in 0..src.len() {
+        let add = src[(x + radius + 1).min(last)];
+        let remove = src[x.saturating_sub(radius)];
+        for c in 0..3 {
+            dst[x][c] = (sum[c] / window) as u8;
+            sum[c] = sum[c] + add[c] as u32 - remove[c] as u32;
+        }
+    }
+}
+
+/// Image formats the frozen image can be saved as
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Png,
+    Jpeg { quality: u8 },
+    Ppm,
+}
+
+impl Format {
+    /// Looks up a format by its name or file extension
+    pub fn from_name(name: &str, quality: u8) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "png" => Some(Format::Png),
+            "jpeg" | "jpg" => Some(Format::Jpeg { quality }),
+            "ppm" => Some(Format::Ppm),
+            _ => None,
+        }
+    }
+}
+
+/// Encodes tightly packed RGBA pixels as a PNG and writes them to `dst`
+pub fn encode_rgba_png(rgba: &[u8], width: u32, height: u32, dst: impl Write) -> Result<()> {
+    let mut encoder = png::Encoder::new(dst, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(rgba)?;
+    writer.finish()?;
+
+    Ok(())
+}
+
+/// Encodes tightly packed RGB pixels and writes them to `dst`
+fn encode(rgb: &[u8], width: u32, height: u32, format: Format, mut dst: impl Write) -> Result<()> {
+    match format {
+        Format::Png => {
+            let mut encoder = png::Encoder::new(dst, width, height);
+            encoder.set_color(png::ColorType::Rgb);
+            encoder.set_depth(png::BitDepth::Eight);
+            let mut writer = encoder.write_header()?;
+            writer.write_image_data(rgb)?;
+            writer.finish()?;
+        }
+        Format::Jpeg { quality } => {
+            if width > u16::MAX as u32 || height > u16::MAX as u32 {
+                bail!("JPEG does not support images larger than 65535x65535");
+            }
+
+            jpeg_encoder::Encoder::new(dst, quality).encode(
+                rgb,
+                width as u16,
+                height as u16,
+                jpeg_encoder::ColorType::Rgb,
+            )?;
+        }
+        Format::Ppm => {
+            write!(dst, "P6\n{} {}\n255\n", width, height)?;
+            dst.write_all(rgb)?;
+            dst.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Padding bytes of the frames built by `test_frame`
+#[cfg(test)]
+pub(crate) const TEST_PADDING: u8 = 0xee;
+
+/// A frame of `region` from tightly packed `pixels` whose rows are followed by `padding` bytes
+/// of `TEST_PADDING`, the pixel size follows from the length of `pixels`
+#[cfg(test)]
+pub(crate) fn test_frame(
+    region: Rect,
+    pixels: &[u8],
+    padding: usize,
+    depth: i32,
+    pixel_format: PixelFormat,
+) -> Frame {
+    let row = pixels.len() / region.height as usize;
+    let bits_per_pixel = (row / region.width as usize * 8) as i32;
+    let data = pixels
+        .chunks(row)
+        .flat_map(|row| row.iter().copied().chain(vec![TEST_PADDING; padding]))
+        .collect();
+
+    let mut frame = Frame::new(data, region, row + padding, depth, bits_per_pixel).unwrap();
+    frame.set_pixel_format(pixel_format);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A region of `width`x`height` at 0,0
+    fn region(width: u16, height: u16) -> Rect {
+        Rect {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        }
+    }
+
+    /// A 2x2 frame with 4 bytes per pixel whose rows are padded by 4 bytes
+    fn padded(data: [[u8; 8]; 2], depth: i32) -> Frame {
+        test_frame(region(2, 2), &data.concat(), 4, depth, PixelFormat::Bgrx)
+    }
+
+    const ORDERS: [ByteOrder; 6] = [
+        ByteOrder::Rgba,
+        ByteOrder::Bgra,
+        ByteOrder::Argb,
+        ByteOrder::Abgr,
+        ByteOrder::Rgb,
+        ByteOrder::Bgr,
+    ];
+
+    /// A 2x2 frame of `rgb` in `pixel_format`, the rows are padded by 3 bytes
+    fn layout(pixel_format: PixelFormat, bytes_per_pixel: usize, rgb: [u8; 3]) -> Frame {
+        let mut pixel = pixel_format.swizzle(rgb).to_vec();
+        pixel.resize(bytes_per_pixel, 0);
+        test_frame(region(2, 2), &pixel.repeat(4), 3, 24, pixel_format)
+    }
+
+    #[test]
+    fn to_bytes_writes_every_order_from_every_layout() {
+        let [r, g, b] = [0x11, 0x22, 0x33];
+        let expected = |order| match order {
+            ByteOrder::Rgba => vec![r, g, b, 0xff],
+            ByteOrder::Bgra => vec![b, g, r, 0xff],
+            ByteOrder::Argb => vec![0xff, r, g, b],
+            ByteOrder::Abgr => vec![0xff, b, g, r],
+            ByteOrder::Rgb => vec![r, g, b],
+            ByteOrder::Bgr => vec![b, g, r],
+        };
+
+        for pixel_format in [PixelFormat::Rgbx, PixelFormat::Bgrx] {
+            for bytes_per_pixel in [3, 4] {
+                let frame = layout(pixel_format, bytes_per_pixel, [r, g, b]);
+                for order in ORDERS {
+                    assert_eq!(
+                        frame.to_bytes(order).unwrap(),
+                        expected(order).repeat(4),
+                        "{:?} with {} bytes per pixel to {:?}",
+                        pixel_format,
+                        bytes_per_pixel,
+                        order
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn to_bytes_round_trips_through_every_order() {
+        let rgb = [0x11, 0x22, 0x33];
+
+        for pixel_format in [PixelFormat::Rgbx, PixelFormat::Bgrx] {
+            for bytes_per_pixel in [3, 4] {
+                let frame = layout(pixel_format, bytes_per_pixel, rgb);
+                let rgba = frame.to_bytes(ByteOrder::Rgba).unwrap();
+
+                // The orders that are also a layout a frame can have, read back without padding
+                for (order, format) in [
+                    (ByteOrder::Rgba, PixelFormat::Rgbx),
+                    (ByteOrder::Bgra, PixelFormat::Bgrx),
+                    (ByteOrder::Rgb, PixelFormat::Rgbx),
+                    (ByteOrder::Bgr, PixelFormat::Bgrx),
+                ] {
+                    let size = order.bytes_per_pixel();
+                    let mut back = Frame::new(
+                        frame.to_bytes(order).unwrap(),
+                        frame.region(),
+                        2 * size,
+                        24,
+                        size as i32 * 8,
+                    )
+                    .unwrap();
+                    back.set_pixel_format(format);
+
+                    assert_eq!(back.byte_order().unwrap(), order);
+                    assert_eq!(back.to_bytes(ByteOrder::Rgba).unwrap(), rgba);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn encode_png_stores_the_chosen_order() {
+        let frame = layout(PixelFormat::Bgrx, 4, [0x11, 0x22, 0x33]);
+
+        for (order, color_type) in [
+            (ByteOrder::Rgba, png::ColorType::Rgba),
+            (ByteOrder::Bgr, png::ColorType::Rgb),
+        ] {
+            let mut encoded = Vec::new();
+            frame.encode_png(order, &mut encoded).unwrap();
+
+            let mut reader = png::Decoder::new(&encoded[..]).read_info().unwrap();
+            let mut pixels = vec![0; reader.output_buffer_size()];
+            let info = reader.next_frame(&mut pixels).unwrap();
+
+            assert_eq!(info.color_type, color_type);
+            assert_eq!(pixels, frame.to_bytes(order).unwrap());
+        }
+    }
+
+    #[test]
+    fn byte_order_matches_the_layout() {
+        for (pixel_format, bytes_per_pixel, order) in [
+            (PixelFormat::Rgbx, 3, ByteOrder::Rgb),
+            (PixelFormat::Rgbx, 4, ByteOrder::Rgba),
+            (PixelFormat::Bgrx, 3, ByteOrder::Bgr),
+            (PixelFormat::Bgrx, 4, ByteOrder::Bgra),
+        ] {
+            let frame = layout(pixel_format, bytes_per_pixel, [1, 2, 3]);
+            assert_eq!(frame.byte_order().unwrap(), order);
+        }
+    }
+
+    #[test]
+    fn make_opaque_only_sets_the_alpha_of_pixels() {
+        let mut frame = padded(
+            [[1, 2, 3, 0, 4, 5, 6, 0x80], [7, 8, 9, 0x10, 1, 2, 3, 0]],
+            32,
+        );
+        frame.make_opaque();
+
+        assert_eq!(
+            frame.data(),
+            [
+                1,
+                2,
+                3,
+                0xff,
+                4,
+                5,
+                6,
+                0xff,
+                TEST_PADDING,
+                TEST_PADDING,
+                TEST_PADDING,
+                TEST_PADDING, //
+                7,
+                8,
+                9,
+                0xff,
+                1,
+                2,
+                3,
+                0xff,
+                TEST_PADDING,
+                TEST_PADDING,
+                TEST_PADDING,
+                TEST_PADDING,
+            ]
+        );
+    }
+
+    #[test]
+    fn set_depth_32_makes_the_padding_opaque() {
+        let mut frame = padded([[1, 2, 3, 0, 4, 5, 6, 0], [7, 8, 9, 0, 1, 2, 3, 0]], 24);
+        frame.set_depth(32).unwrap();
+
+        let data = frame.data();
+        assert_eq!([data[3], data[7], data[15], data[19]], [0xff; 4]);
+        assert_eq!(data[8..12], [TEST_PADDING; 4]);
+    }
+
+    #[test]
+    fn make_opaque_leaves_depth_24_alone() {
+        let mut frame = padded(
+            [[1, 2, 3, 0, 4, 5, 6, 0x80], [7, 8, 9, 0x10, 1, 2, 3, 0]],
+            24,
+        );
+        let before = frame.data().to_vec();
+        frame.make_opaque();
+
+        assert_eq!(frame.data(), &before[..]);
+    }
+
+    #[test]
+    fn blur_with_a_radius_of_0_changes_nothing() {
+        let pixels: Vec<u8> = (0..36).map(|i| i * 7).collect();
+        let mut frame = test_frame(region(3, 3), &pixels, 4, 32, PixelFormat::Bgrx);
+        let before = frame.data().to_vec();
+        frame.blur(0).unwrap();
+
+        assert_eq!(frame.data(), &before[..]);
+    }
+
+    #[test]
+    fn blur_keeps_a_uniform_image_uniform() {
+        let pixel = [10, 20, 30, 0x80];
+        let mut frame = test_frame(region(5, 3), &pixel.repeat(15), 2, 32, PixelFormat::Bgrx);
+        frame.blur(2).unwrap();
+
+        for row in frame.data().chunks(frame.stride()) {
+            assert_eq!(row[..20], pixel.repeat(5)[..]);
+            assert_eq!(row[20..], [TEST_PADDING; 2]);
+        }
+    }
+
+    #[test]
+    fn blur_clamps_at_the_edges_of_a_one_pixel_wide_frame() {
+        let pixels = [0, 0, 0, 0x40, 255, 255, 255, 0x40];
+        let mut frame = test_frame(region(1, 2), &pixels, 4, 32, PixelFormat::Bgrx);
+        frame.blur(1).unwrap();
+
+        // Three box blurs of 0 and 255 where the closest pixel stands in past the edges
+        assert_eq!(
+            frame.data(),
+            [
+                122,
+                122,
+                122,
+                0x40,
+                TEST_PADDING,
+                TEST_PADDING,
+                TEST_PADDING,
+                TEST_PADDING, //
+                131,
+                131,
+                131,
+                0x40,
+                TEST_PADDING,
+                TEST_PADDING,
+                TEST_PADDING,
+                TEST_PADDING,
+            ]
+        );
+    }
+
+    #[test]
+    fn blur_leaves_the_alpha_and_padding_alone() {
+        let pixels: Vec<u8> = (0..36).map(|i| i * 7).collect();
+        let mut frame = test_frame(region(3, 3), &pixels, 4, 32, PixelFormat::Bgrx);
+        let before = frame.data().to_vec();
+        frame.blur(1).unwrap();
+
+        for (row, before) in frame.data().chunks(16).zip(before.chunks(16)) {
+            assert_ne!(row[..12], before[..12]);
+            assert_eq!(
+                [row[3], row[7], row[11]],
+                [before[3], before[7], before[11]]
+            );
+            assert_eq!(row[12..], [TEST_PADDING; 4]);
+        }
+    }
+
+    #[test]
+    fn tint_parses_rrggbbaa() {
+        let tint = Tint {
+            r: 0xff,
+            g: 0x10,
+            b: 0x00,
+            a: 0x80,
+        };
+        assert_eq!("ff100080".parse::<Tint>().unwrap(), tint);
+        assert_eq!("#FF100080".parse::<Tint>().unwrap(), tint);
+    }
+
+    #[test]
+    fn tint_rejects_bad_lengths_and_digits() {
+        for tint in [
+            "",
+            "ff1000",
+            "ff10008",
+            "ff1000800",
+            "#ff10008",
+            "gg100080",
+            "+f100080",
+        ] {
+            assert!(tint.parse::<Tint>().is_err(), "{:?}", tint);
+        }
+    }
+
+    #[test]
+    fn dim_blends_the_tint_over_the_dimmed_pixel() {
+        let tint = "ff000080".parse().unwrap();
+
+        for (pixel_format, pixel, dimmed) in [
+            (PixelFormat::Bgrx, [100, 150, 200, 7], [25, 37, 178, 7]),
+            (PixelFormat::Rgbx, [200, 150, 100, 7], [178, 37, 25, 7]),
+        ] {
+            let mut frame = test_frame(region(1, 1), &pixel, 0, 24, pixel_format);
+            frame.dim(0.5, Some(tint)).unwrap();
+            assert_eq!(frame.data(), dimmed);
+        }
+    }
+
+    #[test]
+    fn new_rejects_empty_regions() {
+        for (width, height) in [(0, 2), (2, 0), (0, 0)] {
+            let error = Frame::new(vec![0; 16], region(width, height), 8, 24, 32)
+                .err()
+                .unwrap();
+            assert_eq!(
+                error.to_string(),
+                format!("A {}x{} image has no pixels", width, height)
+            );
+        }
+    }
+
+    #[test]
+    fn grayscale_weights_the_channels_in_their_order() {
+        for (rgb, luminance) in [
+            ([255, 0, 0], 54),
+            ([0, 255, 0], 182),
+            ([0, 0, 255], 19),
+            ([255, 255, 255], 255),
+        ] {
+            for pixel_format in [PixelFormat::Bgrx, PixelFormat::Rgbx] {
+                let mut frame = layout(pixel_format, 4, rgb);
+                frame.grayscale().unwrap();
+                assert_eq!(frame.data()[..3], [luminance; 3], "{:?}", pixel_format);
+            }
+        }
+    }
+
+    #[test]
+    fn grayscale_leaves_the_alpha_and_padding_alone() {
+        let mut frame = padded(
+            [[1, 2, 3, 0, 4, 5, 6, 0x80], [7, 8, 9, 0x10, 1, 2, 3, 0]],
+            32,
+        );
+        frame.grayscale().unwrap();
+
+        let data = frame.data();
+        assert_eq!([data[3], data[7], data[15], data[19]], [0, 0x80, 0x10, 0]);
+        assert_eq!(data[8..12], [TEST_PADDING; 4]);
+        assert_eq!(data[20..24], [TEST_PADDING; 4]);
+    }
+
+    #[test]
+    fn downscale_rounds_the_size_and_keeps_at_least_a_pixel() {
+        for ((width, height), factor, size) in [
+            ((4, 4), 0.5, (2, 2)),
+            ((3, 3), 0.5, (2, 2)),
+            ((4, 2), 0.001, (1, 1)),
+            ((5, 3), 1.0, (5, 3)),
+        ] {
+            let pixels = vec![0; width as usize * height as usize * 4];
+            let frame = test_frame(region(width, height), &pixels, 0, 24, PixelFormat::Bgrx);
+            let scaled = frame.downscale(factor).unwrap();
+            assert_eq!(
+                (scaled.width(), scaled.height()),
+                size,
+                "{}x{}",
+                width,
+                height
+            );
+        }
+    }
+
+    #[test]
+    fn downscale_averages_every_box() {
+        let pixels = [
+            [
+                0, 0, 0, 0, 10, 20, 30, 40, 100, 100, 100, 100, 200, 200, 200, 200,
+            ],
+            [
+                20, 40, 60, 80, 31, 41, 51, 61, 100, 100, 100, 100, 101, 101, 101, 101,
+            ],
+        ];
+        let region = Rect {
+            x: 7,
+            y: 9,
+            width: 4,
+            height: 2,
+        };
+        let frame = test_frame(region, &pixels.concat(), 4, 32, PixelFormat::Rgbx);
+        let scaled = frame.downscale(0.5).unwrap();
+
+        // Rounded to the nearest value, the padding is not part of the new rows
+        assert_eq!(scaled.data(), [15, 25, 35, 45, 125, 125, 125, 125]);
+        assert_eq!(scaled.stride(), 8);
+        assert_eq!(
+            scaled.region(),
+            Rect {
+                x: 7,
+                y: 9,
+                width: 2,
+                height: 1,
+            }
+        );
+        assert_eq!(scaled.pixel_format(), PixelFormat::Rgbx);
+        assert_eq!((scaled.depth(), scaled.bits_per_pixel()), (32, 32));
+    }
+
+    #[test]
+    fn downscale_of_an_odd_size_covers_every_pixel_once() {
+        let pixels: Vec<u8> = [1, 2, 3, 4, 5, 6, 7, 8, 9]
+            .iter()
+            .flat_map(|&v| [v * 10; 4])
+            .collect();
+        let frame = test_frame(region(3, 3), &pixels, 0, 24, PixelFormat::Bgrx);
+        let scaled = frame.downscale(0.5).unwrap();
+
+        // The first row and column stay alone, the rest is averaged in boxes of 2
+        let boxes: Vec<u8> = scaled.data().chunks(4).map(|pixel| pixel[0]).collect();
+        assert_eq!(boxes, [10, 25, 55, 70]);
+    }
+
+    #[test]
+    fn new_rejects_data_too_short_for_the_region() {
+        let error = |data: usize, stride| {
+            Frame::new(vec![0; data], region(2, 2), stride, 24, 32)
+                .err()
+                .unwrap()
+                .to_string()
+        };
+
+        // One byte short of the last row, and rows narrower than two pixels
+        assert_eq!(
+            error(15, 8),
+            "15 bytes with a stride of 8 are too few for a 2x2 image"
+        );
+        assert_eq!(
+            error(16, 7),
+            "16 bytes with a stride of 7 are too few for a 2x2 image"
+        );
+        assert!(Frame::new(vec![0; 16], region(2, 2), 8, 24, 32).is_ok());
+    }
+}
@@ -0,0 +1,936 @@
+//! Freezes the screen of an X display by showing a capture of it in a window on top
+//!
+//! ```no_run
+//! # fn main() -> anyhow::Result<()> {
+//! let freezer = nora::Freezer::new(None)?;
+//! let frame = freezer.capture(None)?;
+//! let frozen = freezer.show(&frame)?;
+//! // Select something on the frozen screen
+//! frozen.teardown();
+//! # Ok(())
+//! # }
+//! ```
+
+use std::os::raw::{c_char, c_long, c_ulong};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use log::{debug, info, warn};
+use x11::xlib::{
+    Colormap, CurrentTime, IncludeInferiors, MSBFirst, Pixmap, PropModeReplace, RevertToParent,
+    StructureNotifyMask, Visual, Window, ZPixmap, GC, XA_ATOM, XA_CARDINAL, XA_STRING, XA_WM_CLASS,
+    XA_WM_CLIENT_MACHINE, XA_WM_NAME,
+};
+
+pub mod clipboard;
+pub mod ffi;
+pub mod geometry;
+pub mod image;
+
+pub use image::Frame;
+
+use image::PixelFormat;
+
+use ffi::{Display, Image, Screen};
+use geometry::Rect;
+
+// Name and class of the window unless they are changed
+const DEFAULT_WM_NAME: &str = "fullscreen-viewer";
+
+// How long the window manager gets to map a window without override-redirect
+const MANAGED_MAP_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Sets all bits to 1 - mask everything
+const ALL_PLANES: u32 = !0;
+
+/// Copies the pixels of `image`, which shows `region` of the screen, into a frame
+fn frame_from_image(image: &Image, region: Rect) -> Result<Frame> {
+    debug!(
+        "Image has a depth of {}, {} bits per pixel and a stride of {} bytes",
+        image.depth, image.bits_per_pixel, image.bytes_per_line
+    );
+
+    // Everything after this walks the rows by the stride and assumes they are all there
+    let row_bits = region.width as i64 * image.bits_per_pixel as i64;
+    if image.data.is_null()
+        || image.width != region.width as i32
+        || image.height != region.height as i32
+        || image.bits_per_pixel <= 0
+        || (image.bytes_per_line as i64) * 8 < row_bits
+    {
+        bail!(
+            "The X server returned a {}x{} image with {} bytes per row for the {}x{} region, \
+             it has a depth of {}, {} bits per pixel and rows padded to {} bits",
+            image.width,
+            image.height,
+            image.bytes_per_line,
+            region.width,
+            region.height,
+            image.depth,
+            image.bits_per_pixel,
+            image.bitmap_pad
+        );
+    }
+    if image.bitmap_pad > 0 {
+        let pad = image.bitmap_pad as i64;
+        let expected = (row_bits + pad - 1) / pad * pad / 8;
+        if image.bytes_per_line as i64 != expected {
+            debug!(
+                "Rows are {} bytes instead of the {} a padding of {} bits gives",
+                image.bytes_per_line, expected, pad
+            );
+        }
+    }
+
+    let mut frame = Frame::new(
+        image.data().to_vec(),
+        region,
+        image.bytes_per_line as usize,
+        image.depth,
+        image.bits_per_pixel,
+    )?;
+
+    let detected = PixelFormat::detect(
+        image.red_mask,
+        image.blue_mask,
+        image.bits_per_pixel,
+        image.byte_order == MSBFirst,
+    );
+    match detected {
+        Some(pixel_format) => frame.set_pixel_format(pixel_format),
+        None => info!(
+            "Unknown channel layout with a red mask of {:#x} and a blue mask of {:#x}, \
+             assuming BGR(X)",
+            image.red_mask, image.blue_mask
+        ),
+    }
+    debug!("Pixel format is {:?}", frame.pixel_format());
+
+    Ok(frame)
+}
+
+/// The name of this machine for WM_CLIENT_MACHINE
+fn hostname() -> Option<String> {
+    let mut buffer = [0u8; 256];
+    if unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut c_char, buffer.len()) } != 0 {
+        return None;
+    }
+
+    // Truncated names are not guaranteed to be terminated
+    let end = buffer.iter().position(|&byte| byte == 0)?;
+    String::from_utf8(buffer[..end].to_vec()).ok()
+}
+
+/// A pixmap that is freed when dropped, so returning early on errors does not leak it
+struct PixmapGuard<'a> {
+    display: &'a Display,
+    handle: Pixmap,
+}
+
+impl Drop for PixmapGuard<'_> {
+    fn drop(&mut self) {
+        self.display.free_pixmap(self.handle);
+    }
+}
+
+/// Where the window goes in the stacking order once it is mapped
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StackMode {
+    Above,
+    Below,
+    /// Leaves the window where the server maps it
+    Unchanged,
+}
+
+impl StackMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "above" => Some(StackMode::Above),
+            "below" => Some(StackMode::Below),
+            "none" => Some(StackMode::Unchanged),
+            _ => None,
+        }
+    }
+}
+
+/// Blends the premultiplied `pixels` of a cursor covering `cursor_rect` of the screen onto
+/// `frame`
+#[cfg(feature = "cursor")]
+fn blend_cursor(frame: &mut Frame, pixels: &[ffi::CPixel], cursor_rect: Rect) -> Result<()> {
+    let bytes_per_pixel = frame.bytes_per_pixel()?;
+    let region = frame.region();
+    let stride = frame.stride();
+    let pixel_format = frame.pixel_format();
+    let image_data = frame.data_mut();
+
+    let visible = match cursor_rect.intersect(region) {
+        Some(visible) => visible,
+        None => return Ok(()),
+    };
+
+    for sx in visible.x as i32..visible.right() {
+        for sy in visible.y as i32..visible.bottom() {
+            let (ix, iy) = (sx - region.x as i32, sy - region.y as i32);
+            let cx = (sx - cursor_rect.x as i32) as usize;
+            let cy = (sy - cursor_rect.y as i32) as usize;
+
+            let istart = iy as usize * stride + ix as usize * bytes_per_pixel;
+            let cstart = cy * cursor_rect.width as usize + cx;
+
+            // The colors are premultiplied, only the image needs to be scaled by the alpha
+            let pixel = pixels[cstart];
+            let inverse_alpha = 255 - pixel.a() as u32;
+            let color = pixel_format.swizzle([pixel.r(), pixel.g(), pixel.b()]);
+
+            for (i, &channel) in color.iter().enumerate() {
+                let old = (image_data[istart + i] as u32 * inverse_alpha + 127) / 255;
+                image_data[istart + i] = (old + channel as u32).min(255) as u8;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// A connection to an X display and the screen on it to freeze
+pub struct Freezer {
+    display: Display,
+    screen: Screen,
+    depth: i32,
+    visual: *mut Visual,
+    bypass_compositor: bool,
+    take_focus: bool,
+    override_redirect: bool,
+    sync_before_capture: bool,
+    stack_mode: StackMode,
+    fullscreen_monitors: Option<[u32; 4]>,
+    border: Option<(u16, [u8; 3])>,
+    wm_name: String,
+    wm_class: (String, String),
+}
+
+impl Freezer {
+    /// Connects to `display`, or to `$DISPLAY` if it is `None`, and uses its default screen
+    pub fn new(display: Option<&str>) -> Result<Self> {
+        let display = Display::open(display)?;
+        let screen = display.screen(display.default_screen())?;
+
+        Ok(Freezer {
+            depth: screen.root_depth,
+            visual: screen.root_visual,
+            display,
+            screen,
+            bypass_compositor: true,
+            take_focus: true,
+            override_redirect: true,
+            sync_before_capture: true,
+            stack_mode: StackMode::Above,
+            fullscreen_monitors: None,
+            border: None,
+            wm_name: DEFAULT_WM_NAME.to_string(),
+            wm_class: (DEFAULT_WM_NAME.to_string(), DEFAULT_WM_NAME.to_string()),
+        })
+    }
+
+    /// Freezes screen `id` of the display instead of the default one
+    pub fn set_screen(&mut self, id: usize) -> Result<()> {
+        let screen_count = self.display.screen_count() as usize;
+        if id >= screen_count {
+            bail!(
+                "Screen {} does not exist, the display has {} screen(s)",
+                id,
+                screen_count
+            );
+        }
+
+        self.screen = self.display.screen(id as i32)?;
+        self.depth = self.screen.root_depth;
+        self.visual = self.screen.root_visual;
+        Ok(())
+    }
+
+    /// Shows the freeze with a TrueColor visual of `depth` instead of the root visual
+    ///
+    /// Captures are converted to it, which works between depths of 24 and 32.
+    pub fn set_depth(&mut self, depth: i32) -> Result<()> {
+        self.visual = self.display.match_visual(&self.screen, depth)?;
+        self.depth = depth;
+        Ok(())
+    }
+
+    /// Whether the window asks compositors to stop compositing while it is shown, on by default
+    pub fn set_bypass_compositor(&mut self, bypass: bool) {
+        self.bypass_compositor = bypass;
+    }
+
+    /// Whether the window takes the input focus while it is shown, on by default
+    pub fn set_take_focus(&mut self, take_focus: bool) {
+        self.take_focus = take_focus;
+    }
+
+    /// Whether the window bypasses the window manager, on by default
+    ///
+    /// Without it the window manager maps the window and is asked through `_NET_WM_STATE` to
+    /// make it fullscreen in the chosen layer, which some tiling window managers handle better.
+    pub fn set_override_redirect(&mut self, override_redirect: bool) {
+        self.override_redirect = override_redirect;
+    }
+
+    /// Whether `capture` waits for the server to process every earlier request, on by default
+    ///
+    /// Costs a round trip but keeps drawing that is still queued up from tearing the capture.
+    pub fn set_sync_before_capture(&mut self, sync: bool) {
+        self.sync_before_capture = sync;
+    }
+
+    /// Where the window is stacked, above all other windows by default
+    pub fn set_stack_mode(&mut self, stack_mode: StackMode) {
+        self.stack_mode = stack_mode;
+    }
+
+    /// Lets the window manager place the window over the monitors with these top, bottom, left
+    /// and right indices through `_NET_WM_FULLSCREEN_MONITORS` instead of using override-redirect
+    ///
+    /// The window is still created over the region, for window managers without support.
+    pub fn set_fullscreen_monitors(&mut self, monitors: Option<[u32; 4]>) {
+        self.fullscreen_monitors = monitors;
+    }
+
+    /// Draws a border of the given width and RGB color around the outside of the window
+    pub fn set_border(&mut self, border: Option<(u16, [u8; 3])>) {
+        self.border = border;
+    }
+
+    /// Sets the WM_NAME of the window, `fullscreen-viewer` by default
+    pub fn set_wm_name(&mut self, name: &str) {
+        self.wm_name = name.to_string();
+    }
+
+    /// Sets the instance and class name in WM_CLASS of the window, `fullscreen-viewer` by default
+    pub fn set_wm_class(&mut self, instance: &str, class: &str) -> Result<()> {
+        if instance.contains('\0') || class.contains('\0') {
+            bail!("WM_CLASS names can not contain NUL bytes");
+        }
+
+        self.wm_class = (instance.to_string(), class.to_string());
+        Ok(())
+    }
+
+    pub fn display(&self) -> &Display {
+        &self.display
+    }
+
+    pub fn screen(&self) -> &Screen {
+        &self.screen
+    }
+
+    /// Captures `region` of the screen or all of it
+    ///
+    /// No window, pixmap or GC is created for it, so screenshots that are only saved leave no
+    /// trace on screen. `show` is what puts the freeze up.
+    pub fn capture(&self, region: Option<Rect>) -> Result<Frame> {
+        let mut frame = self.capture_raw(region)?;
+        self.finish_capture(&mut frame)?;
+        Ok(frame)
+    }
+
+    /// Captures like `capture` but keeps the pixels exactly as the X server returned them
+    ///
+    /// `finish_capture` turns the frame into what `capture` returns.
+    pub fn capture_raw(&self, region: Option<Rect>) -> Result<Frame> {
+        let region = region.unwrap_or_else(|| self.screen.rect());
+        info!("Capturing {}", region);
+
+        let root = self.screen.root;
+
+        // XSync waits for the reply to a GetInputFocus, which the server only sends once it is
+        // done with everything requested before
+        if self.sync_before_capture {
+            self.display.sync(false);
+        }
+
+        // Prefer MIT-SHM and only transfer the pixels over the connection if that fails
+        let shm_image = self.display.shm_get_image(
+            root,
+            self.screen.root_visual,
+            self.screen.root_depth,
+            region,
+            ALL_PLANES,
+        );
+        let image = match shm_image {
+            Ok(image) => {
+                info!("Captured the screen through MIT-SHM");
+                image
+            }
+            Err(e) => {
+                info!("Capturing the screen without MIT-SHM: {}", e);
+                self.display
+                    .get_image(root, region, ALL_PLANES, ZPixmap)
+                    .context("Failed to capture the screen")?
+            }
+        };
+
+        frame_from_image(&image, region)
+    }
+
+    /// Converts a frame from `capture_raw` to the depth of the freeze and makes it opaque
+    pub fn finish_capture(&self, frame: &mut Frame) -> Result<()> {
+        frame
+            .set_depth(self.depth)
+            .context("Failed to convert the capture")?;
+        // A root window with an ARGB visual can hold pixels that are not opaque
+        frame.make_opaque();
+
+        Ok(())
+    }
+
+    /// Blends the cursor onto `frame` where it is on screen right now
+    #[cfg(feature = "cursor")]
+    pub fn draw_cursor(&self, frame: &mut Frame) -> Result<()> {
+        let cursor = self
+            .display
+            .get_cursor_image()
+            .context("Failed to get cursor image")?;
+
+        let cursor_rect = Rect {
+            x: cursor.x() - cursor.xhot() as i16,
+            y: cursor.y() - cursor.yhot() as i16,
+            width: cursor.width(),
+            height: cursor.height(),
+        };
+        blend_cursor(frame, cursor.pixels(), cursor_rect)
+    }
+
+    /// Shows `frame` in a window on top of everything else where it was captured
+    ///
+    /// The window takes the input focus unless that was turned off.
+    pub fn show(&self, frame: &Frame) -> Result<FrozenWindow<'_>> {
+        let display = &self.display;
+        let region = frame.region();
+
+        // Without a background the image is drawn onto the window once, which stays as long as
+        // nothing covers the window
+        let pixmap = match self.create_pixmap(region) {
+            Ok(pixmap) => pixmap,
+            Err(e) => {
+                warn!("{:#}, drawing the frozen image onto the window instead", e);
+                let frozen = self.show_pixmap(None, region)?;
+                display
+                    .put_frame(frozen.window, frozen.gc, self.visual, frame, 0, 0)
+                    .context("Failed to draw the frozen image")?;
+
+                return Ok(frozen);
+            }
+        };
+        let gc_handle = display.create_gc(pixmap.handle);
+
+        let put = display.put_frame(pixmap.handle, gc_handle, self.visual, frame, 0, 0);
+        display.free_gc(gc_handle);
+        put.context("Failed to draw the frozen image")?;
+
+        self.show_pixmap(Some(pixmap), region)
+    }
+
+    /// Shows what is on screen in `region`, or all of it, without capturing it first
+    ///
+    /// The server copies the contents of the root window itself so no pixels pass through
+    /// nora, but there is no `Frame` to process or save either.
+    pub fn show_root(&self, region: Option<Rect>) -> Result<FrozenWindow<'_>> {
+        let display = &self.display;
+        let region = region.unwrap_or_else(|| self.screen.rect());
+        info!("Copying {} of the root window", region);
+
+        if self.depth != self.screen.root_depth {
+            bail!(
+                "The root window can only be copied with its own depth of {}",
+                self.screen.root_depth
+            );
+        }
+
+        // A window without a background does not paint over what is on screen when mapped,
+        // which freezes it just the same until something covers the window
+        let pixmap = match self.create_pixmap(region) {
+            Ok(pixmap) => pixmap,
+            Err(e) => {
+                warn!("{:#}, leaving the window without a background instead", e);
+                return self.show_pixmap(None, region);
+            }
+        };
+        let gc_handle = display.create_gc(pixmap.handle);
+
+        // Copy what the windows show instead of only the root background
+        display.set_subwindow_mode(gc_handle, IncludeInferiors);
+        display.copy_area(self.screen.root, pixmap.handle, gc_handle, region, 0, 0);
+        display.free_gc(gc_handle);
+
+        self.show_pixmap(Some(pixmap), region)
+    }
+
+    fn create_pixmap(&self, region: Rect) -> Result<PixmapGuard<'_>> {
+        let handle = self.display.create_pixmap(
+            self.screen.root,
+            region.width as u32,
+            region.height as u32,
+            self.depth as u32,
+        )?;
+
+        Ok(PixmapGuard {
+            display: &self.display,
+            handle,
+        })
+    }
+
+    /// Shows `pixmap` in a window covering `region` and frees it
+    ///
+    /// Without a pixmap the window has no background and keeps what is on screen below it.
+    fn show_pixmap(&self, pixmap: Option<PixmapGuard>, region: Rect) -> Result<FrozenWindow<'_>> {
+        let display = &self.display;
+        let root = self.screen.root;
+
+        let colormap = if self.visual == self.screen.root_visual {
+            0
+        } else {
+            display.create_colormap(root, self.visual)
+        };
+        let window_handle = display.create_window(
+            root as i32,
+            region,
+            self.depth,
+            self.visual,
+            colormap,
+            pixmap.as_ref().map_or(0, |pixmap| pixmap.handle),
+        );
+
+        // The window keeps its background after the pixmap is freed
+        drop(pixmap);
+
+        // Drawn around the region so it does not cover any of the frozen image
+        if let Some((width, rgb)) = self.border {
+            let pixel = ffi::opaque_pixel(self.visual, self.depth, rgb);
+            display.set_window_border(window_handle, region, width, pixel);
+        }
+
+        // Destroys the window again if setting it up fails
+        let previous_focus = self.take_focus.then(|| display.get_input_focus());
+        let black = ffi::opaque_pixel(self.visual, self.depth, [0, 0, 0]);
+        let white = ffi::opaque_pixel(self.visual, self.depth, [255, 255, 255]);
+        let frozen = FrozenWindow {
+            display,
+            window: window_handle,
+            gc: display.create_gc(window_handle),
+            visual: self.visual,
+            depth: self.depth,
+            colormap,
+            black,
+            white,
+            region,
+            previous_focus,
+        };
+
+        // Setup window properties
+        display.change_property(
+            window_handle,
+            XA_WM_NAME,
+            XA_STRING,
+            PropModeReplace,
+            self.wm_name.as_bytes(),
+        );
+
+        display.change_property(
+            window_handle,
+            XA_WM_CLASS,
+            XA_STRING,
+            PropModeReplace,
+            // Two NUL terminated strings
+            format!("{}\0{}\0", self.wm_class.0, self.wm_class.1).as_bytes(),
+        );
+
+        let atoms = display
+            .intern_atoms(
+                [
+                    "_NET_WM_BYPASS_COMPOSITOR",
+                    "_NET_WM_STATE",
+                    "_NET_WM_STATE_FULLSCREEN",
+                    "_NET_WM_STATE_ABOVE",
+                    "_NET_WM_STATE_BELOW",
+                    "_NET_WM_WINDOW_TYPE",
+                    "_NET_WM_WINDOW_TYPE_NORMAL",
+                    "_NET_WM_FULLSCREEN_MONITORS",
+                    "_NET_WM_PID",
+                ],
+                false,
+            )
+            .context("Failed to get the window property atoms")?;
+        let [bypass, state, fullscreen, above, below, window_type, normal, monitors_hint, pid] =
+            atoms;
+
+        // Lets tools like xprop and window managers tell which process the window belongs to,
+        // the pid only means something together with the machine it runs on
+        if let Some(hostname) = hostname() {
+            display.change_property(
+                window_handle,
+                XA_WM_CLIENT_MACHINE,
+                XA_STRING,
+                PropModeReplace,
+                hostname.as_bytes(),
+            );
+            display.change_property(
+                window_handle,
+                pid,
+                XA_CARDINAL,
+                PropModeReplace,
+                &[std::process::id() as c_ulong],
+            );
+        }
+
+        if self.bypass_compositor {
+            display.change_property(
+                window_handle,
+                bypass,
+                XA_CARDINAL,
+                PropModeReplace,
+                &[1 as c_ulong],
+            );
+        }
+
+        // Ask for fullscreen in the chosen layer, window managers read this when mapping a
+        // managed window while override-redirect windows are only inspected by compositors
+        let states = match self.stack_mode {
+            StackMode::Above => vec![fullscreen, above],
+            StackMode::Below => vec![fullscreen, below],
+            StackMode::Unchanged => vec![fullscreen],
+        };
+        display.change_property(window_handle, state, XA_ATOM, PropModeReplace, &states);
+
+        display.change_property(
+            window_handle,
+            window_type,
+            XA_ATOM,
+            PropModeReplace,
+            &[normal],
+        );
+
+        let managed = !self.override_redirect || self.fullscreen_monitors.is_some();
+        if managed {
+            display.set_override_redirect(window_handle, false);
+            // To see when the window manager mapped it
+            display.select_input(window_handle, StructureNotifyMask);
+        }
+
+        // The window manager places the window instead, if it honors the hint
+        if let Some(monitors) = self.fullscreen_monitors {
+            display.change_property(
+                window_handle,
+                monitors_hint,
+                XA_CARDINAL,
+                PropModeReplace,
+                &monitors.map(|monitor| monitor as c_ulong),
+            );
+        }
+
+        // Make window visible
+        display.map_window(window_handle);
+
+        // EWMH only asks window managers to read the hint from this message
+        if let Some([top, bottom, left, right]) = self.fullscreen_monitors {
+            let data = [top, bottom, left, right, 1].map(|value| value as c_long);
+            if let Err(e) = display.send_client_message(root, window_handle, monitors_hint, data) {
+                warn!("{:#}", e);
+            }
+        }
+
+        // Focusing the window before it is viewable fails
+        if managed {
+            if !display.wait_for_map(window_handle, MANAGED_MAP_TIMEOUT) {
+                warn!("The window manager did not map the frozen window in time");
+            }
+            display.select_input(window_handle, 0);
+        }
+
+        match self.stack_mode {
+            StackMode::Above => display.set_stack_mode(window_handle, x11::xlib::Above),
+            StackMode::Below => display.set_stack_mode(window_handle, x11::xlib::Below),
+            StackMode::Unchanged => (),
+        }
+
+        // Ensure that commands have completed
+        display.sync(false);
+
+        if self.take_focus {
+            display.set_input_focus(window_handle, RevertToParent, CurrentTime);
+        }
+
+        Ok(frozen)
+    }
+}
+
+/// The window showing a frozen frame, it is torn down when dropped
+pub struct FrozenWindow<'a> {
+    display: &'a Display,
+    window: Window,
+    gc: GC,
+    visual: *mut Visual,
+    depth: i32,
+    colormap: Colormap,
+    // Pixel values for outlines
+    black: c_ulong,
+    white: c_ulong,
+    region: Rect,
+    // The focused window and what the focus reverted to before, if the focus was taken
+    previous_focus: Option<(Window, i32)>,
+}
+
+impl FrozenWindow<'_> {
+    pub fn id(&self) -> Window {
+        self.window
+    }
+
+    /// Where on the screen the window is
+    pub fn region(&self) -> Rect {
+        self.region
+    }
+
+    /// Replaces the frozen image with `frame`, which has to cover the whole window
+    pub fn set_frame(&self, frame: &Frame) -> Result<()> {
+        if frame.region() != self.region {
+            bail!(
+                "The image covers {} instead of the frozen {}",
+                frame.region(),
+                self.region
+            );
+        }
+
+        let pixmap = PixmapGuard {
+            display: self.display,
+            handle: self
+                .display
+                .create_pixmap(
+                    self.window,
+                    self.region.width as u32,
+                    self.region.height as u32,
+                    self.depth as u32,
+                )
+                .context("Failed to replace the frozen image")?,
+        };
+        self.display
+            .put_frame(pixmap.handle, self.gc, self.visual, frame, 0, 0)
+            .context("Failed to draw the frozen image")?;
+
+        // Like at creation the window keeps the background after the pixmap is freed
+        self.display
+            .set_window_background(self.window, pixmap.handle);
+        self.display.clear_area(
+            self.window,
+            Rect {
+                x: 0,
+                y: 0,
+                ..self.region
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Takes the window off the screen until `map` is called, so what is below it can be
+    /// captured again
+    ///
+    /// Grabs on the window end with this and have to be taken again after mapping it.
+    pub fn unmap(&self) {
+        self.display.unmap_window(self.window);
+        self.display.sync(false);
+    }
+
+    /// Puts the window back on the screen after `unmap` and focuses it again if it had the focus
+    pub fn map(&self) {
+        self.display.map_window(self.window);
+        self.display.sync(false);
+
+        if self.previous_focus.is_some() {
+            if let Err(e) =
+                self.display
+                    .try_set_input_focus(self.window, RevertToParent, CurrentTime)
+            {
+                debug!("Failed to focus the frozen window again: {:#}", e);
+            }
+        }
+    }
+
+    /// Draws `frame` over the frozen image at its region, which is in screen coordinates
+    pub fn draw(&self, frame: &Frame) -> Result<()> {
+        let at = frame.region();
+        self.display.put_frame(
+            self.window,
+            self.gc,
+            self.visual,
+            frame,
+            at.x as i32 - self.region.x as i32,
+            at.y as i32 - self.region.y as i32,
+        )
+    }
+
+    /// Shows the frozen image again in `rect`, which is in screen coordinates
+    pub fn clear(&self, rect: Rect) {
+        let rect = Rect {
+            x: rect.x - self.region.x,
+            y: rect.y - self.region.y,
+            ..rect
+        };
+        self.display.clear_area(self.window, rect);
+    }
+
+    /// Captures what the window shows now, including anything other clients drew onto it
+    pub fn capture(&self) -> Result<Frame> {
+        let rect = Rect {
+            x: 0,
+            y: 0,
+            ..self.region
+        };
+        let image = self
+            .display
+            .get_image(self.window, rect, ALL_PLANES, ZPixmap)
+            .context("Failed to capture the frozen window")?;
+
+        // Other clients could have drawn with any alpha onto a window with an ARGB visual
+        let mut frame = frame_from_image(&image, self.region)?;
+        frame.make_opaque();
+        Ok(frame)
+    }
+
+    /// Draws a two pixel wide outline inside of `rect`, which is in screen coordinates
+    ///
+    /// The outer line is white and the inner one black so it shows on any image.
+    pub fn outline(&self, rect: Rect) {
+        let rect = Rect {
+            x: rect.x - self.region.x,
+            y: rect.y - self.region.y,
+            ..rect
+        };
+
+        self.display.set_foreground(self.gc, self.white);
+        self.display.draw_rectangle(self.window, self.gc, rect);
+        if rect.width > 2 && rect.height > 2 {
+            let inner = Rect {
+                x: rect.x + 1,
+                y: rect.y + 1,
+                width: rect.width - 2,
+                height: rect.height - 2,
+            };
+            self.display.set_foreground(self.gc, self.black);
+            self.display.draw_rectangle(self.window, self.gc, inner);
+        }
+    }
+
+    /// Removes an outline drawn with `outline` in `rect` again
+    pub fn clear_outline(&self, rect: Rect) {
+        for edge in rect.edges(2) {
+            self.clear(edge);
+        }
+    }
+
+    /// Destroys the window and gives the focus back
+    pub fn teardown(self) {}
+}
+
+impl Drop for FrozenWindow<'_> {
+    fn drop(&mut self) {
+        self.display.free_gc(self.gc);
+        self.display.destroy_window(self.window);
+        if self.colormap != 0 {
+            self.display.free_colormap(self.colormap);
+        }
+
+        // There is nothing left to restore if the window was destroyed in the meantime
+        if let Some((focus, revert_to)) = self.previous_focus {
+            let _ = self
+                .display
+                .try_set_input_focus(focus, revert_to, CurrentTime);
+        }
+
+        self.display.sync(false);
+    }
+}
+
+#[cfg(all(test, feature = "cursor"))]
+mod tests {
+    use super::*;
+    use ffi::CPixel;
+
+    const BACKGROUND: [u8; 4] = [100, 150, 200, 0];
+
+    /// A 3x2 frame at 10,10 with a padding of one pixel after every row
+    fn frame(pixel_format: PixelFormat) -> Frame {
+        let region = Rect {
+            x: 10,
+            y: 10,
+            width: 3,
+            height: 2,
+        };
+        image::test_frame(region, &BACKGROUND.repeat(6), 4, 24, pixel_format)
+    }
+
+    #[test]
+    fn blend_cursor_blends_premultiplied_pixels() {
+        // The top row is above the frame, the bottom one is opaque red and half white
+        let pixels = [0xff00_ff00, 0xff00_00ff, 0xffff_0000, 0x8080_8080].map(CPixel::from_argb);
+        let cursor = Rect {
+            x: 11,
+            y: 9,
+            width: 2,
+            height: 2,
+        };
+
+        for (pixel_format, red) in [
+            (PixelFormat::Bgrx, [0, 0, 255, 0]),
+            (PixelFormat::Rgbx, [255, 0, 0, 0]),
+        ] {
+            let mut frame = frame(pixel_format);
+            blend_cursor(&mut frame, &pixels, cursor).unwrap();
+
+            let data = frame.data();
+            assert_eq!(data[0..4], BACKGROUND);
+            assert_eq!(data[4..8], red);
+            assert_eq!(data[8..12], [178, 203, 228, 0]);
+            // The padding and the second row are left alone
+            assert_eq!(data[12..16], [image::TEST_PADDING; 4]);
+            assert_eq!(data[16..28], BACKGROUND.repeat(3)[..]);
+        }
+    }
+
+    #[test]
+    fn blend_cursor_outside_of_the_frame_changes_nothing() {
+        let pixels = [CPixel::from_argb(0xffff_ffff)];
+        let cursor = Rect {
+            x: 13,
+            y: 12,
+            width: 1,
+            height: 1,
+        };
+
+        let untouched = frame(PixelFormat::Bgrx);
+        let mut frame = frame(PixelFormat::Bgrx);
+        blend_cursor(&mut frame, &pixels, cursor).unwrap();
+        assert_eq!(frame.data(), untouched.data());
+    }
+
+    #[test]
+    fn blend_cursor_across_rows_leaves_the_padding_alone() {
+        // The right column of the cursor would land on the padding after both rows
+        let pixels = [0xffff_ffff; 4].map(CPixel::from_argb);
+        let cursor = Rect {
+            x: 12,
+            y: 10,
+            width: 2,
+            height: 2,
+        };
+
+        let mut frame = frame(PixelFormat::Bgrx);
+        blend_cursor(&mut frame, &pixels, cursor).unwrap();
+
+        let data = frame.data();
+        for row in data.chunks(16) {
+            assert_eq!(row[..8], BACKGROUND.repeat(2)[..]);
+            assert_eq!(row[8..12], [255, 255, 255, 0]);
+            assert_eq!(row[12..], [image::TEST_PADDING; 4]);
+        }
+    }
+}
@@ -1,159 +1,2542 @@
-use std::ffi::OsString;
+use std::ffi::{OsStr, OsString};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Read, Write};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use x11::keysym::{XK_Escape, XK_c};
 use x11::xlib::{
-    CurrentTime, PropModeReplace, RevertToParent, ZPixmap, XA_CARDINAL, XA_STRING, XA_WM_CLASS,
-    XA_WM_NAME,
+    AlreadyGrabbed, ButtonPressMask, ButtonReleaseMask, CurrentTime, ExposureMask, GrabSuccess,
+    KeyPressMask, PointerMotionMask, Time, Window,
 };
 
-use anyhow::{anyhow, Context, Result};
-use structopt::clap::AppSettings::TrailingVarArg;
+use anyhow::{anyhow, bail, Context, Result};
+use log::{debug, info, Level, LevelFilter};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use structopt::clap::{self, AppSettings::TrailingVarArg};
 use structopt::StructOpt;
 
-mod ffi;
-use ffi::Display;
+use nora::clipboard::Clipboard;
+use nora::ffi::{Display, Event, Monitor};
+use nora::geometry::Rect;
+use nora::{image, Frame, Freezer, FrozenWindow, StackMode};
 
-// Sets all bits to 1 - mask everything
-const ALL_PLANES: u32 = !0;
+mod config;
+mod control;
+
+// Replaced with the path of the image by --capture-temp
+const PLACEHOLDER: &str = "{}";
+
+// How long the windows below get to redraw before a recapture from --control-socket
+const RECAPTURE_DELAY: Duration = Duration::from_millis(100);
+
+// How often --inhibit-idle resets the screensaver, shorter than any timeout worth setting
+const IDLE_RESET_INTERVAL: Duration = Duration::from_secs(30);
+
+// What the pointer grabs of --grab and --select report
+const POINTER_EVENTS: i64 = ButtonPressMask | ButtonReleaseMask | PointerMotionMask;
+
+// Set by --quiet
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Prints a warning to stderr unless --quiet was given
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        if !QUIET.load(Ordering::Relaxed) {
+            eprintln!("WARNING: {}", format_args!($($arg)*));
+        }
+    };
+}
 
 #[derive(StructOpt, Debug)]
 #[structopt(
-    global_settings = &[TrailingVarArg],)
-]
+    global_settings = &[TrailingVarArg],
+    after_help = "ENVIRONMENT:\n    \
+        The executable is started with these variables set:\n    \
+        NORA_WINDOW_ID        XID of the window showing the frozen image\n    \
+        NORA_SCREEN_WIDTH     Width of the frozen area\n    \
+        NORA_SCREEN_HEIGHT    Height of the frozen area",
+)]
 struct Args {
-    #[structopt(short, long)]
-    /// Add the cursor to the frozen image
+    // The cursor is drawn by default now, this only keeps old invocations working
+    #[allow(dead_code)]
+    #[structopt(short, long, hidden = true)]
     show_cursor: bool,
-    #[structopt(required = true)]
-    /// Executable with arguments to run
+    #[structopt(short, long, parse(from_occurrences))]
+    /// Print what nora is doing, repeat for more details
+    verbose: u8,
+    #[structopt(short, long, conflicts_with = "verbose")]
+    /// Only print errors, stdout is only ever used for --output -
+    quiet: bool,
+    #[structopt(long)]
+    /// Print to stderr how long connecting, capturing, processing, saving and showing the frozen
+    /// image took
+    time: bool,
+    #[structopt(long)]
+    /// Ignore ~/.config/nora/config.toml, which can set defaults for --dim, --delay,
+    /// --freeze-duration, --monitor and the executable
+    no_config: bool,
+    #[structopt(long, conflicts_with = "show-cursor")]
+    /// Leave the cursor out of the frozen image, only the live cursor then moves over the freeze
+    // Builds without the cursor feature never draw it but still accept the flag
+    #[cfg_attr(not(feature = "cursor"), allow(dead_code))]
+    no_cursor: bool,
+    #[structopt(long)]
+    /// X display to connect to instead of $DISPLAY, also passed on to the executable
+    display: Option<String>,
+    #[structopt(long, default_value = "0")]
+    /// Retry connecting to the display this many times with a growing delay, for hotkeys that
+    /// can fire before the X server is ready
+    connect_retries: u32,
+    #[structopt(long, parse(try_from_str = parse_seconds))]
+    /// Keep retrying to connect to the display for up to this many seconds
+    connect_timeout: Option<f32>,
+    #[structopt(long)]
+    /// WM_NAME of the frozen window instead of fullscreen-viewer, lets compositor rules tell
+    /// nora based tools apart
+    wm_name: Option<String>,
+    #[structopt(long)]
+    /// Instance and class name in WM_CLASS of the frozen window instead of fullscreen-viewer
+    wm_class: Option<String>,
+    #[structopt(long)]
+    /// Leave the input focus where it is instead of focusing the frozen window, keys are then
+    /// only read with --wait-key or --grab
+    no_focus: bool,
+    #[structopt(long, default_value = "above", possible_values = &["above", "below", "none"])]
+    /// Where the frozen window is stacked, below puts it behind the other windows as a backdrop
+    /// and none leaves it where it is mapped
+    stack: String,
+    #[structopt(long)]
+    /// Let the window manager map the frozen window and ask it for fullscreen in the --stack
+    /// layer instead of bypassing it, for window managers that mishandle override-redirect
+    no_override_redirect: bool,
+    #[structopt(long)]
+    /// Capture without waiting for the X server to finish the requests it already got, saves
+    /// a round trip but animations may tear
+    no_sync_before_capture: bool,
+    #[structopt(long)]
+    /// Let the compositor keep compositing the frozen window, can avoid tearing with some
+    /// compositors
+    no_compositor_bypass: bool,
+    #[structopt(long)]
+    /// Screen to freeze instead of the default one
+    screen: Option<usize>,
+    #[structopt(long)]
+    /// Show the freeze with this depth instead of the one of the root window, 24 or 32
+    depth: Option<i32>,
+    #[structopt(long)]
+    /// Monitor to freeze, given by its RandR output name, its index, `current` for the one
+    /// containing the pointer or `active` for the one showing most of the active window
+    monitor: Option<String>,
+    #[structopt(
+        long,
+        conflicts_with_all = &["monitor", "geometry", "window"],
+        parse(try_from_str = parse_monitor_indices),
+    )]
+    /// Freeze the monitors with these top,bottom,left,right indices from --list-monitors and let
+    /// the window manager place the window through _NET_WM_FULLSCREEN_MONITORS instead of
+    /// bypassing it, which works better with docks on window managers that support the hint
+    monitors: Option<[u32; 4]>,
+    #[structopt(long)]
+    /// Print which of the X extensions nora uses the server supports and exit
+    features: bool,
+    #[structopt(
+        long,
+        requires = "output",
+        conflicts_with_all = &[
+            "executable", "hold", "select", "no-exec", "capture-selection",
+            "screenshot-on-child-success", "capture-temp", "format", "scale",
+        ],
+    )]
+    /// Save only the current cursor with its transparency as a PNG to --output and exit, without
+    /// freezing anything. Needs XFixes
+    cursor_only: bool,
+    #[structopt(long)]
+    /// Print the index, name, geometry and primary flag of each monitor for --monitor and exit
+    list_monitors: bool,
+    #[structopt(long, conflicts_with = "monitor")]
+    /// Only freeze the area given as WxH+X+Y
+    geometry: Option<Rect>,
+    #[structopt(long, parse(try_from_str = parse_border))]
+    /// Draw a border around the frozen area given as WIDTH:RRGGBB, like 2:ff0000, it goes
+    /// outside of the area so none of it is covered
+    border: Option<(u16, [u8; 3])>,
+    #[structopt(long, default_value = "1", parse(try_from_str = parse_factor))]
+    /// Darken the frozen image by multiplying its colors with a factor between 0 and 1
+    dim: f32,
+    #[structopt(long)]
+    /// Turn the frozen image gray, before it is dimmed and tinted
+    grayscale: bool,
+    #[structopt(long)]
+    /// Blend a color given as RRGGBBAA in hex over the frozen image, after dimming it
+    tint: Option<image::Tint>,
+    #[structopt(long, default_value = "0")]
+    /// Blur the frozen image with the given radius in pixels
+    blur: u32,
+    #[structopt(long, conflicts_with_all = &["monitor", "geometry"], parse(try_from_str = parse_xid))]
+    /// Only freeze the visible part of the window with this XID
+    window: Option<Window>,
+    #[structopt(short, long)]
+    /// Save the frozen image before running the executable, - writes it to stdout
+    output: Option<PathBuf>,
+    #[structopt(
+        long,
+        conflicts_with_all = &["monitor", "monitors", "geometry", "window", "root-fallback"],
+    )]
+    /// Also save the part of the frozen image each monitor shows to DIR/NAME.png, named after
+    /// the monitors in --list-monitors
+    output_per_monitor: Option<PathBuf>,
+    #[structopt(long, hidden = true)]
+    /// Write the captured pixels to this file and their layout to FILE.txt, for debugging
+    /// broken freezes. They are converted to straight RGBA, --byte-order native leaves them
+    /// untouched
+    dump_raw: Option<PathBuf>,
+    #[structopt(long, possible_values = &["png", "jpeg", "ppm"])]
+    /// Format of the saved images, guessed from the file extension of --output and PNG for
+    /// --output-per-monitor by default
+    format: Option<String>,
+    #[structopt(long, default_value = "1", parse(try_from_str = parse_factor))]
+    /// Shrink the image saved to --output by a factor between 0 and 1, the freeze itself keeps
+    /// the full resolution
+    scale: f32,
+    #[structopt(long, conflicts_with = "no-exec")]
+    /// Take commands on a Unix socket at this path while the screen is frozen, one per line:
+    /// recapture freezes what is on screen now, save PATH saves what the frozen window shows and
+    /// quit unfreezes like Escape, terminating the executable
+    control_socket: Option<PathBuf>,
+    #[structopt(long, require_equals = true, min_values = 0, validator = validate_fd)]
+    /// Print the window, region, screen and executable pid as JSON after starting the
+    /// executable, to stderr or with --print-json=FD to that file descriptor
+    print_json: Option<Option<RawFd>>,
+    #[structopt(long, default_value = "auto", possible_values = &["auto", "rgbx", "bgrx"])]
+    /// Byte order of the color channels in the captured pixels, detected from the visual by
+    /// default. Only needed if saved images have red and blue swapped
+    pixel_format: String,
+    #[structopt(
+        long,
+        possible_values = &["rgba", "bgra", "argb", "abgr", "rgb", "bgr", "native"],
+    )]
+    /// Byte order of the pixels in --dump-raw and PNG images, straight RGBA for --dump-raw and
+    /// RGB for PNG by default. native keeps the order of the capture, with --dump-raw also its
+    /// row padding
+    byte_order: Option<String>,
+    #[structopt(long, default_value = "90", parse(try_from_str = parse_quality))]
+    /// Quality of saved JPEG images between 1 and 100
+    quality: u8,
+    #[structopt(long, default_value = "0", parse(try_from_str = parse_seconds))]
+    /// Seconds to wait before taking the screenshot, fractions are allowed
+    delay: f32,
+    #[structopt(long)]
+    /// Show a zoomed in view of the frozen image next to the pointer
+    magnifier: bool,
+    #[structopt(long, default_value = "4")]
+    /// How many times the magnifier enlarges the frozen image
+    magnifier_zoom: u16,
+    #[structopt(long, default_value = "200")]
+    /// Width and height of the magnifier in pixels
+    magnifier_size: u16,
+    #[structopt(long, default_value = "0")]
+    /// Milliseconds to wait after showing the frozen image before running the executable, gives
+    /// compositors time to put it on screen
+    freeze_delay_after_map: u64,
+    #[structopt(long)]
+    /// Wait for a key press before running the executable, Escape cancels
+    wait_key: bool,
+    #[structopt(long)]
+    /// Grab the keyboard and pointer so no input reaches other windows before the executable
+    /// runs
+    grab: bool,
+    #[structopt(long)]
+    /// Keep the pointer inside the frozen area with XFixes pointer barriers while it is frozen
+    pointer_barrier: bool,
+    #[structopt(long)]
+    /// Keep the screensaver and DPMS from blanking the screen while it is frozen
+    inhibit_idle: bool,
+    #[structopt(long, requires = "grab")]
+    /// Keep the grabs while the executable is running
+    grab_through: bool,
+    #[structopt(long, conflicts_with = "hold", parse(try_from_str = parse_seconds))]
+    /// Seconds after which the executable is terminated and the screen unfrozen, exits with 124
+    timeout: Option<f32>,
+    #[structopt(long, default_value = "60", parse(try_from_str = parse_seconds))]
+    /// Unfreeze after this many seconds at the latest, even if the executable is still running.
+    /// nora then waits for it to exit unless --kill-after-freeze is given. 0 never unfreezes
+    /// early, --hold ends the freeze on its own
+    freeze_duration: f32,
+    #[structopt(long, conflicts_with = "hold")]
+    /// Terminate the executable like on SIGTERM when --freeze-duration unfreezes the screen
+    kill_after_freeze: bool,
+    #[structopt(long, conflicts_with = "executable", parse(try_from_str = parse_seconds))]
+    /// Keep the screen frozen for this many seconds instead of running an executable, with
+    /// --wait-key Escape ends it early. Pressing c copies the frozen image to the clipboard,
+    /// nora then keeps running after the freeze to serve it until something else is copied
+    hold: Option<f32>,
+    #[structopt(
+        long,
+        requires = "output",
+        conflicts_with_all = &["executable", "hold", "no-exec", "capture-selection"],
+    )]
+    /// Drag a rectangle over the frozen screen with the left mouse button and only save that area
+    /// to --output instead of running an executable, Escape cancels
+    select: bool,
+    #[structopt(long, requires = "output", conflicts_with_all = &["hold", "no-exec"])]
+    /// Only save the area the executable prints to stdout as WxH+X+Y or X Y W H, like slop
+    capture_selection: bool,
+    #[structopt(
+        long,
+        requires = "output",
+        conflicts_with_all = &["hold", "no-exec", "capture-selection", "select", "magnifier"],
+    )]
+    /// Save what the frozen window shows once the executable exits successfully instead of the
+    /// image it started with. Executables can draw annotations onto the window in
+    /// $NORA_WINDOW_ID, nothing is saved if they exit with another status
+    screenshot_on_child_success: bool,
+    #[structopt(long, conflicts_with_all = &["hold", "no-exec"])]
+    /// Save the frozen image as a temporary PNG and replace {} in the arguments of the
+    /// executable with its path, it is removed once the executable exits
+    capture_temp: bool,
+    #[structopt(long, requires = "capture-temp")]
+    /// Leave the temporary image of --capture-temp in place
+    keep_temp: bool,
+    #[structopt(
+        long,
+        conflicts_with_all = &["output", "tint", "grayscale", "magnifier", "capture-temp"],
+    )]
+    /// Let the X server copy the screen into the freeze instead of capturing it, faster on large
+    /// or slow displays. Nothing is captured so the image can not be saved, dimmed, blurred,
+    /// magnified or copied, and the cursor is left out
+    root_fallback: bool,
+    #[structopt(long, conflicts_with = "hold")]
+    /// Only save the image without freezing the screen or running anything
+    no_exec: bool,
+    #[structopt(long, conflicts_with_all = &["no-exec", "cursor-only"])]
+    /// Move into the background once the screen is frozen so the shell that started nora
+    /// returns, stdin, stdout and stderr are then redirected to /dev/null
+    fork: bool,
+    #[structopt(
+        long,
+        conflicts_with_all = &["hold", "select", "no-exec", "fork", "cursor-only"],
+    )]
+    /// Unfreeze once the executable exits successfully, then freeze again and run it once more
+    /// until it fails or a signal arrives. Waits --delay before every freeze, with --wait-key
+    /// each one waits for a key and Escape ends the repetition
+    repeat: bool,
+    #[structopt(long, requires = "repeat", parse(try_from_str = parse_count))]
+    /// Stop --repeat after the executable ran this many times
+    count: Option<u32>,
+    /// Executable with arguments to run, can be left out with --output or --hold
     executable: Vec<OsString>,
 }
 
-fn main() {
-    if let Err(e) = run() {
-        eprint!("ERROR: {}", e);
-        e.chain()
+impl Args {
+    /// Fills in what was not given on the command line from the config file
+    fn apply_config(&mut self, config: config::Config, matches: &clap::ArgMatches) -> Result<()> {
+        let given = |name| matches.occurrences_of(name) > 0;
+
+        if let Some(dim) = config.dim.filter(|_| !given("dim")) {
+            self.dim = parse_factor(&dim).context("Invalid dim in the config")?;
+        }
+        if let Some(delay) = config.delay.filter(|_| !given("delay")) {
+            self.delay = parse_seconds(&delay).context("Invalid delay in the config")?;
+        }
+        if let Some(duration) = config.freeze_duration.filter(|_| !given("freeze-duration")) {
+            self.freeze_duration =
+                parse_seconds(&duration).context("Invalid freeze_duration in the config")?;
+        }
+
+        let area_given = [
+            "monitor",
+            "monitors",
+            "geometry",
+            "window",
+            "output-per-monitor",
+        ]
+        .iter()
+        .any(|&name| given(name));
+        if let Some(monitor) = config.monitor.filter(|_| !area_given) {
+            self.monitor = Some(monitor);
+        }
+
+        // These run without an executable and conflict with one
+        let needs_executable = self.hold.is_none()
+            && !self.no_exec
+            && !self.select
+            && !self.cursor_only
+            && !self.list_monitors
+            && !self.features;
+        if let Some(executable) = config.executable {
+            if self.executable.is_empty() && needs_executable {
+                self.executable = executable;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks the combinations of arguments clap can not express
+    fn validate(&self) -> Result<(), clap::Error> {
+        if self.executable.is_empty()
+            && self.output.is_none()
+            && self.output_per_monitor.is_none()
+            && self.hold.is_none()
+            && !self.list_monitors
+            && !self.features
+        {
+            return Err(clap::Error::with_description(
+                "No executable given, nothing would happen without one unless --output or --hold \
+                 is used",
+                clap::ErrorKind::MissingRequiredArgument,
+            ));
+        }
+
+        // Either of them can be given, clap only knows requiring one
+        let saves = self.output.is_some() || self.output_per_monitor.is_some();
+        if self.format.is_some() && !saves {
+            return Err(clap::Error::with_description(
+                "--format needs --output or --output-per-monitor to save anything",
+                clap::ErrorKind::MissingRequiredArgument,
+            ));
+        }
+        if self.no_exec && !saves {
+            return Err(clap::Error::with_description(
+                "--no-exec needs --output or --output-per-monitor, it only saves the image",
+                clap::ErrorKind::MissingRequiredArgument,
+            ));
+        }
+
+        if self.repeat && self.executable.is_empty() {
+            return Err(clap::Error::with_description(
+                "--repeat needs an executable to run again",
+                clap::ErrorKind::MissingRequiredArgument,
+            ));
+        }
+        if self.repeat && self.output.as_deref() == Some(Path::new("-")) {
+            return Err(clap::Error::with_description(
+                "--output - can only be written once, --repeat would append every run to stdout",
+                clap::ErrorKind::ArgumentConflict,
+            ));
+        }
+
+        if self.capture_selection && self.executable.is_empty() {
+            return Err(clap::Error::with_description(
+                "--capture-selection needs an executable that prints the selection",
+                clap::ErrorKind::MissingRequiredArgument,
+            ));
+        }
+
+        let has_placeholder = self
+            .executable
+            .iter()
             .skip(1)
-            .for_each(|cause| eprint!(": {}", cause));
-        eprintln!();
-        std::process::exit(1);
+            .any(|arg| arg.to_str().is_some_and(|arg| arg.contains(PLACEHOLDER)));
+        if self.capture_temp && !has_placeholder {
+            return Err(clap::Error::with_description(
+                "--capture-temp needs {} in the arguments of the executable to put the image \
+                 path in",
+                clap::ErrorKind::MissingRequiredArgument,
+            ));
+        }
+
+        // Both have defaults, which clap would count as conflicting
+        if self.root_fallback && (self.dim < 1.0 || self.blur > 0) {
+            return Err(clap::Error::with_description(
+                "--root-fallback does not capture an image that --dim or --blur could change",
+                clap::ErrorKind::ArgumentConflict,
+            ));
+        }
+
+        if self.pointer_barrier && !cfg!(feature = "cursor") {
+            return Err(clap::Error::with_description(
+                "--pointer-barrier needs XFixes, which this build of nora leaves out",
+                clap::ErrorKind::InvalidValue,
+            ));
+        }
+
+        if self.cursor_only && !cfg!(feature = "cursor") {
+            return Err(clap::Error::with_description(
+                "--cursor-only needs XFixes, which this build of nora leaves out",
+                clap::ErrorKind::InvalidValue,
+            ));
+        }
+
+        if self.magnifier_zoom == 0 || self.magnifier_size == 0 {
+            return Err(clap::Error::with_description(
+                "--magnifier-zoom and --magnifier-size have to be larger than 0",
+                clap::ErrorKind::InvalidValue,
+            ));
+        }
+
+        let saved_late = self.capture_selection || self.select || self.screenshot_on_child_success;
+        if self.fork && saved_late && self.output.as_deref() == Some(Path::new("-")) {
+            return Err(clap::Error::with_description(
+                "--output - is only written once the freeze ends, after --fork detached stdout",
+                clap::ErrorKind::ArgumentConflict,
+            ));
+        }
+
+        if self.print_json == Some(Some(1)) && self.output.as_deref() == Some(Path::new("-")) {
+            return Err(clap::Error::with_description(
+                "--print-json=1 would end up in the image written to stdout by --output -",
+                clap::ErrorKind::ArgumentConflict,
+            ));
+        }
+
+        // Only inherited descriptors are open this early, later ones could be nora's own
+        if let Some(Some(fd)) = self.print_json {
+            if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+                return Err(clap::Error::with_description(
+                    &format!("--print-json={} is not an open file descriptor", fd),
+                    clap::ErrorKind::InvalidValue,
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn main() {
+    match run() {
+        Ok(code) => std::process::exit(code),
+        Err(e) => {
+            eprint!("ERROR: {}", e);
+            e.chain().skip(1).for_each(|cause| eprint!(": {}", cause));
+            eprintln!();
+            std::process::exit(1);
+        }
     }
 }
 
-fn run() -> Result<()> {
-    let mut args = Args::from_args();
+/// Returns the exit code nora should exit with
+fn run() -> Result<i32> {
+    let matches = Args::clap().get_matches();
+    let mut args = Args::from_clap(&matches);
+    if let Some(path) = config::path().filter(|_| !args.no_config) {
+        let config = config::load(&path)?;
+        args.apply_config(config, &matches)?;
+    }
+    if let Err(e) = args.validate() {
+        e.exit();
+    }
+
+    QUIET.store(args.quiet, Ordering::Relaxed);
+    let level = match args.verbose {
+        _ if args.quiet => LevelFilter::Error,
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    // The library's warnings read like those of `warning!`, --quiet already filters them out
+    env_logger::Builder::new()
+        .filter_level(level)
+        .format(|buf, record| match record.level() {
+            Level::Warn => writeln!(buf, "WARNING: {}", record.args()),
+            level => writeln!(buf, "{}: {}", level, record.args()),
+        })
+        .init();
+
+    let mut timings = Timings::default();
+    let mut freezer = timings.measure("connect", || connect(&args))?;
+    if args.features {
+        print_features(freezer.display())?;
+        return Ok(0);
+    }
+    #[cfg(feature = "cursor")]
+    if let (true, Some(path)) = (args.cursor_only, &args.output) {
+        save_cursor(freezer.display(), path)?;
+        return Ok(0);
+    }
+    if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        warning!(
+            "Running through XWayland, only X11 windows can be captured and Wayland \
+             windows may show up black"
+        );
+    }
+    if let Some(id) = args.screen {
+        freezer.set_screen(id)?;
+    }
+    if let Some(depth) = args.depth {
+        freezer.set_depth(depth)?;
+    }
+    freezer.set_bypass_compositor(!args.no_compositor_bypass);
+    freezer.set_take_focus(!args.no_focus);
+    freezer.set_override_redirect(!args.no_override_redirect);
+    freezer.set_sync_before_capture(!args.no_sync_before_capture);
+    freezer.set_fullscreen_monitors(args.monitors);
+    freezer.set_border(args.border);
+    if let Some(stack_mode) = StackMode::from_name(&args.stack) {
+        freezer.set_stack_mode(stack_mode);
+    }
+    if let Some(name) = &args.wm_name {
+        freezer.set_wm_name(name);
+    }
+    if let Some(class) = &args.wm_class {
+        freezer.set_wm_class(class, class)?;
+    }
+    let display = freezer.display();
+    let screen = freezer.screen();
+
+    let screen_id = args.screen.unwrap_or(display.default_screen() as usize);
+    info!("Using screen {} of {}", screen_id, display.screen_count());
+    debug!(
+        "Maximum request size is {} bytes",
+        display.max_request_size()
+    );
 
-    let display = Display::open(None);
-    let screen = display.screen(0);
     let root = screen.root;
-    let (width, height) = (screen.width, screen.height);
+    info!("Root window is {}x{}", screen.width, screen.height);
+    // Some virtual X servers report this while they are still starting up
+    if screen.width == 0 || screen.height == 0 {
+        bail!(
+            "The screen is reported as {}x{}, there is nothing to freeze",
+            screen.width,
+            screen.height
+        );
+    }
+
+    if args.list_monitors {
+        list_monitors(display, root)?;
+        return Ok(0);
+    }
+    check_monitor_layout(display, root, screen.width, screen.height);
+
+    let region = if let Some(query) = &args.monitor {
+        Some(clip_monitor(
+            screen.rect(),
+            find_monitor(display, root, query)?.rect,
+        )?)
+    } else if let Some(indices) = args.monitors {
+        Some(clip_monitor(
+            screen.rect(),
+            monitors_rect(display, root, indices)?,
+        )?)
+    } else if let Some(geometry) = args.geometry {
+        if screen.rect().intersect(geometry) != Some(geometry) {
+            bail!(
+                "The geometry {} does not fit on the {}x{} screen",
+                geometry,
+                screen.width,
+                screen.height
+            );
+        }
+
+        Some(geometry)
+    } else if let Some(window) = args.window {
+        // Capturing from the root shows what is on screen, including overlapping windows,
+        // and always matches the depth of the freeze window
+        let visible = screen
+            .rect()
+            .intersect(display.window_rect(window)?)
+            .with_context(|| anyhow!("Window {:#x} is not on the screen", window))?;
+
+        Some(visible)
+    } else {
+        None
+    };
+
+    // Everything up to here is only done once, --repeat loops over the rest
+    let signal = Arc::new(AtomicUsize::new(0));
+    let mut control = None;
+    let mut runs = 0;
+    loop {
+        if args.delay > 0.0 {
+            std::thread::sleep(Duration::from_secs_f32(args.delay));
+        }
+
+        // The server copies the screen itself when the freeze is shown
+        let frame = if args.root_fallback {
+            None
+        } else {
+            Some(capture_frame(&freezer, region, &args, &mut timings)?)
+        };
+
+        // The selection is only known once the executable is done
+        if let (Some(path), Some(frame)) = (args.output.as_deref(), &frame) {
+            if !args.capture_selection && !args.select && !args.screenshot_on_child_success {
+                timings.measure("save", || save_frame(frame, path, &args))?;
+            }
+        }
+        if let (Some(dir), Some(frame)) = (&args.output_per_monitor, &frame) {
+            save_monitors(display, root, frame, dir, &args)?;
+        }
+
+        if args.no_exec || (args.executable.is_empty() && args.hold.is_none() && !args.select) {
+            if args.time {
+                timings.print();
+            }
+            return Ok(0);
+        }
+
+        // Bound before the first freeze so a bad path does not flash the screen, later runs get
+        // it back from the freeze before
+        if runs == 0 {
+            control = args
+                .control_socket
+                .as_deref()
+                .map(control::ControlSocket::bind)
+                .transpose()?;
+        }
+
+        // Dropped last, after the executable exited
+        let temp_image = match &frame {
+            Some(frame) if args.capture_temp => Some(TempImage::create(frame, args.keep_temp)?),
+            _ => None,
+        };
+
+        let frozen = timings.measure("show", || match &frame {
+            Some(frame) => freezer.show(frame),
+            None => freezer.show_root(region),
+        })?;
+        let window_handle = frozen.id();
+        if args.time {
+            timings.print();
+        }
+
+        if args.freeze_delay_after_map > 0 {
+            std::thread::sleep(Duration::from_millis(args.freeze_delay_after_map));
+        }
+
+        if let Err(e) = display.select_screen_change(root) {
+            debug!("Not watching for screen changes: {:#}", e);
+        }
+        let screen_size = (screen.width, screen.height);
+
+        // Tracked so --control-socket can grab again after recapturing
+        let mut keyboard_grabbed = false;
+        let mut pointer_grabbed = false;
+
+        if args.grab {
+            keyboard_grabbed =
+                retry_grab(|| display.grab_keyboard(window_handle, CurrentTime)) == GrabSuccess;
+            if !keyboard_grabbed {
+                warning!("Failed to grab the keyboard");
+            }
+
+            pointer_grabbed =
+                retry_grab(|| display.grab_pointer(window_handle, POINTER_EVENTS, CurrentTime))
+                    == GrabSuccess;
+            if !pointer_grabbed {
+                warning!("Failed to grab the pointer");
+            }
+        }
+
+        // Exposures wipe the magnifier, keys are read while holding for copying to the clipboard
+        let mut event_mask = ExposureMask;
+        if args.wait_key || args.hold.is_some() || args.select {
+            event_mask |= KeyPressMask;
+        }
+        display.select_input(window_handle, event_mask);
+
+        if args.wait_key {
+            if retry_grab(|| display.grab_keyboard(window_handle, CurrentTime)) != GrabSuccess {
+                bail!("Failed to grab the keyboard");
+            }
+            keyboard_grabbed = true;
+        }
+
+        // With --hold Escape is checked while holding instead
+        if args.wait_key && args.hold.is_none() {
+            // From the second --repeat run on the signal handlers are registered and only set
+            // the flag, so waiting can not block
+            let keysym = loop {
+                if signal.load(Ordering::SeqCst) != 0 {
+                    break None;
+                }
+
+                match display.poll_event() {
+                    Some(Event::KeyPress { keysym, .. }) => break Some(keysym),
+                    Some(Event::ScreenChange { width, height }) => {
+                        warn_screen_change(screen_size, width, height)
+                    }
+                    Some(_) => (),
+                    None => wait_for_input(display, &[], Duration::from_millis(10)),
+                }
+            };
+            if !args.grab {
+                display.ungrab_keyboard(CurrentTime);
+                keyboard_grabbed = false;
+            }
+
+            if keysym.is_none_or(|keysym| keysym == XK_Escape as u64) {
+                frozen.teardown();
+                return Ok(130);
+            }
+        }
+
+        // There is no executable to pass the input to while holding
+        if args.grab && !args.grab_through && args.hold.is_none() {
+            display.ungrab_keyboard(CurrentTime);
+            display.ungrab_pointer(CurrentTime);
+            display.sync(false);
+            keyboard_grabbed = false;
+            pointer_grabbed = false;
+        }
+
+        if args.select {
+            if retry_grab(|| display.grab_pointer(window_handle, POINTER_EVENTS, CurrentTime))
+                != GrabSuccess
+            {
+                bail!("Failed to grab the pointer for the selection");
+            }
+
+            if retry_grab(|| display.grab_keyboard(window_handle, CurrentTime)) != GrabSuccess {
+                bail!("Failed to grab the keyboard");
+            }
+            keyboard_grabbed = true;
+            pointer_grabbed = true;
+        }
+
+        // Dropped before the teardown and on every early return
+        #[cfg(feature = "cursor")]
+        let barriers = if args.pointer_barrier {
+            confine_pointer(display, root, frozen.region())
+        } else {
+            None
+        };
+
+        // Before any threads are started or signal handlers registered, which would not carry over
+        if args.fork {
+            daemonize()?;
+        }
+
+        // Dropped with the barriers, without the extension the loops keep resetting the screensaver
+        let suspension = if args.inhibit_idle {
+            match display.suspend_screensaver() {
+                Ok(suspension) => Some(suspension),
+                Err(e) => {
+                    warning!("{:#}, resetting the screensaver regularly instead", e);
+                    display.reset_screensaver();
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let idle_reset = (args.inhibit_idle && suspension.is_none()).then_some(Instant::now());
+
+        // The handlers only record the signal, the freeze is torn down below
+        if runs == 0 {
+            for &sig in &[SIGINT, SIGTERM] {
+                signal_hook::flag::register_usize(sig, Arc::clone(&signal), sig as usize)
+                    .context("Failed to register signal handler")?;
+            }
+        }
+        // Stops once it is dropped after the freeze
+        let expired = Arc::new(AtomicBool::new(false));
+        let watchdog = (args.freeze_duration > 0.0 && args.hold.is_none())
+            .then(|| start_watchdog(args.freeze_duration, Arc::clone(&expired)));
+
+        let mut freeze = Freeze {
+            freezer: &freezer,
+            display,
+            root,
+            frozen: &frozen,
+            frame: frame.as_ref(),
+            recaptured: None,
+            args: &args,
+            screen_size,
+            magnifier: args.magnifier.then_some(Magnifier {
+                zoom: args.magnifier_zoom,
+                size: args.magnifier_size,
+                pointer: None,
+                shown: None,
+            }),
+            clipboard: None,
+            selection: args.select.then(Selection::default),
+            control: control.take(),
+            idle_reset,
+            quit: false,
+            expired,
+            keyboard_grabbed,
+            pointer_grabbed,
+        };
 
-    let pixmap_handle =
-        display.create_pixmap(root, width as u32, height as u32, screen.root_depth as u32);
+        let outcome = match args.hold {
+            Some(hold) => {
+                Outcome::Exited(hold_freeze(&mut freeze, &signal, hold, args.wait_key), None)
+            }
+            None if args.select => Outcome::Exited(select_region(&mut freeze, &signal), None),
+            None => run_executable(
+                &mut freeze,
+                &signal,
+                &args,
+                screen_id,
+                temp_image.as_ref().map(|image| image.path.as_path()),
+            )?,
+        };
+        drop(watchdog);
+
+        let clipboard = freeze.clipboard.take();
+        control = freeze.control.take();
+        let selected = freeze
+            .selection
+            .as_ref()
+            .and_then(|selection| selection.done);
+        #[cfg(feature = "cursor")]
+        drop(barriers);
+        drop(suspension);
+        let annotated = match outcome {
+            Outcome::Exited(0, _) if args.screenshot_on_child_success => Some(frozen.capture()?),
+            _ => None,
+        };
+        frozen.teardown();
+
+        let (code, stdout) = match outcome {
+            Outcome::Exited(code, stdout) => (code, stdout),
+            Outcome::Outlived(executable) => executable.wait(&signal)?,
+        };
+
+        // Nothing is left to paste it into after a signal
+        if let Some(clipboard) = clipboard.filter(|_| signal.load(Ordering::SeqCst) == 0) {
+            serve_clipboard(display, clipboard, &signal);
+        }
+
+        let selection = match stdout {
+            Some(stdout) if code == 0 => Some(parse_selection(&stdout)?),
+            _ => selected,
+        };
+        if let (Some(path), Some(frame), Some(selection)) = (&args.output, &frame, selection) {
+            info!("Saving the selection {}", selection);
+            save_frame(&frame.crop(selection)?, path, &args)?;
+        }
+
+        if let (Some(path), Some(frame)) = (&args.output, &annotated) {
+            info!("Saving the frozen window as the executable left it");
+            save_frame(frame, path, &args)?;
+        }
+
+        // Another run only starts after a successful one, so cancelling the executable ends it
+        runs += 1;
+        let done = args.count.is_some_and(|count| runs >= count);
+        if !args.repeat || done || code != 0 || signal.load(Ordering::SeqCst) != 0 {
+            return Ok(code);
+        }
+        info!("Freezing again for run {}", runs + 1);
+        timings = Timings::default();
+    }
+}
+
+/// Captures `region` and applies the cursor and image options to it
+fn capture_frame(
+    freezer: &Freezer,
+    region: Option<Rect>,
+    args: &Args,
+    timings: &mut Timings,
+) -> Result<Frame> {
+    let mut frame = timings.measure("capture", || freezer.capture_raw(region))?;
+    let processing = Instant::now();
+    // auto is not a format and keeps the detected one, the dump already uses the override
+    if let Some(pixel_format) = image::PixelFormat::from_name(&args.pixel_format) {
+        frame.set_pixel_format(pixel_format);
+    }
+
+    if let Some(path) = &args.dump_raw {
+        // None keeps the capture as it is
+        let order = match args.byte_order.as_deref() {
+            Some(name) => image::ByteOrder::from_name(name),
+            None => Some(image::ByteOrder::Rgba),
+        };
+        dump_raw(&frame, path, order)?;
+    }
+    freezer.finish_capture(&mut frame)?;
+
+    // The freeze itself works with any image format, only leave out the cursor
+    #[cfg(feature = "cursor")]
+    if !args.no_cursor && freezer.display().has_xfixes() {
+        if let Err(e) = freezer.draw_cursor(&mut frame) {
+            warning!("Failed to draw the cursor: {:#}", e);
+        }
+    }
+
+    apply_effects(&mut frame, args)?;
+
+    timings.phases.push(("process", processing.elapsed()));
+    Ok(frame)
+}
+
+/// Blurs, grays and then dims or tints `frame`, so a tint colours the gray image
+fn apply_effects(frame: &mut Frame, args: &Args) -> Result<()> {
+    if args.blur > 0 {
+        frame.blur(args.blur).context("Can not blur the image")?;
+    }
+
+    if args.grayscale {
+        frame.grayscale().context("Can not turn the image gray")?;
+    }
+
+    if args.dim < 1.0 || args.tint.is_some() {
+        frame
+            .dim(args.dim, args.tint)
+            .context("Can not dim or tint the image")?;
+    }
+
+    Ok(())
+}
+
+/// How long setting up the freeze took, printed with --time
+#[derive(Default)]
+struct Timings {
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl Timings {
+    fn measure<T>(&mut self, name: &'static str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.phases.push((name, start.elapsed()));
+        result
+    }
+
+    /// The total leaves out --delay and anything else nora only waits for
+    fn print(&self) {
+        for (name, duration) in &self.phases {
+            eprintln!("{:<9}{:>10.2?}", name, duration);
+        }
+
+        let total: Duration = self.phases.iter().map(|(_, duration)| *duration).sum();
+        eprintln!("{:<9}{:>10.2?}", "total", total);
+    }
+}
+
+/// Writes the pixels of `frame` to `path` and what is needed to read them to `path.txt`
+///
+/// They are converted to `order` or written as captured without one.
+fn dump_raw(frame: &Frame, path: &Path, order: Option<image::ByteOrder>) -> Result<()> {
+    let converted = order.map(|order| frame.to_bytes(order)).transpose()?;
+    let data = converted.as_deref().unwrap_or_else(|| frame.data());
+    std::fs::write(path, data).with_context(|| anyhow!("Failed to write {}", path.display()))?;
+
+    let mut header_path = path.as_os_str().to_owned();
+    header_path.push(".txt");
+    let header_path = PathBuf::from(header_path);
+
+    let region = frame.region();
+    let header = match order {
+        Some(order) => format!(
+            "x={}\ny={}\nwidth={}\nheight={}\nstride={}\nbyte_order={:?}\nbytes={}\n",
+            region.x,
+            region.y,
+            region.width,
+            region.height,
+            region.width as usize * order.bytes_per_pixel(),
+            order,
+            data.len()
+        ),
+        None => format!(
+            "x={}\ny={}\nwidth={}\nheight={}\nstride={}\ndepth={}\nbits_per_pixel={}\n\
+             pixel_format={:?}\nbytes={}\n",
+            region.x,
+            region.y,
+            region.width,
+            region.height,
+            frame.stride(),
+            frame.depth(),
+            frame.bits_per_pixel(),
+            frame.pixel_format(),
+            frame.data().len()
+        ),
+    };
+    std::fs::write(&header_path, header)
+        .with_context(|| anyhow!("Failed to write {}", header_path.display()))?;
+
+    info!("Dumped the capture to {}", path.display());
+    Ok(())
+}
+
+/// Keeps the freeze up for `seconds` and returns the exit code
+///
+/// Ends early on a signal, on quit from --control-socket or, if `wait_key` is set, on Escape.
+fn hold_freeze(freeze: &mut Freeze, signal: &AtomicUsize, seconds: f32, wait_key: bool) -> i32 {
+    let deadline = Instant::now() + Duration::from_secs_f32(seconds);
+
+    while Instant::now() < deadline {
+        let sig = signal.load(Ordering::SeqCst) as i32;
+        if sig != 0 {
+            return 128 + sig;
+        }
+
+        if (freeze.update() && wait_key) || freeze.quit {
+            return 130;
+        }
+
+        freeze.wait(Duration::from_millis(10));
+    }
+
+    0
+}
+
+/// Waits until a region is selected with the mouse and returns the exit code
+///
+/// Escape, quit from --control-socket, a signal or the end of --freeze-duration cancel the
+/// selection.
+fn select_region(freeze: &mut Freeze, signal: &AtomicUsize) -> i32 {
+    info!("Waiting for a region to be selected");
+
+    loop {
+        let sig = signal.load(Ordering::SeqCst) as i32;
+        if sig != 0 {
+            return 128 + sig;
+        }
+
+        if freeze.update() || freeze.quit || freeze.expired.load(Ordering::SeqCst) {
+            return 130;
+        }
+
+        let done = freeze
+            .selection
+            .as_ref()
+            .is_some_and(|selection| selection.done.is_some());
+        if done {
+            return 0;
+        }
+
+        freeze.wait(Duration::from_millis(10));
+    }
+}
+
+/// Moves nora into the background with a double fork and detaches it from the terminal
+///
+/// Only the grandchild returns. The others exit without running any destructors since those
+/// would tear down the freeze through the X connection they all share.
+fn daemonize() -> Result<()> {
+    io::stdout().flush().context("Failed to flush stdout")?;
+
+    match unsafe { libc::fork() } {
+        -1 => return Err(io::Error::last_os_error()).context("Failed to fork"),
+        0 => (),
+        pid => {
+            let mut status = 0;
+            unsafe { libc::waitpid(pid, &mut status, 0) };
+            unsafe { libc::_exit(exit_code(ExitStatus::from_raw(status))) };
+        }
+    }
+
+    // A new session has no controlling terminal and the grandchild can never get one since it
+    // does not lead the session
+    if unsafe { libc::setsid() } == -1 {
+        eprintln!(
+            "ERROR: Failed to start a new session: {}",
+            io::Error::last_os_error()
+        );
+        unsafe { libc::_exit(1) };
+    }
+    match unsafe { libc::fork() } {
+        -1 => {
+            eprintln!("ERROR: Failed to fork: {}", io::Error::last_os_error());
+            unsafe { libc::_exit(1) };
+        }
+        0 => (),
+        _ => unsafe { libc::_exit(0) },
+    }
+
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .context("Failed to open /dev/null")?;
+    for fd in 0..3 {
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } == -1 {
+            return Err(io::Error::last_os_error()).context("Failed to redirect stdio");
+        }
+    }
+    debug!("Running in the background as {}", std::process::id());
+
+    Ok(())
+}
 
-    let mut image = display.get_image(root, 0, 0, width, height, ALL_PLANES, ZPixmap);
+/// Sets `expired` once the freeze lasted `seconds`, unless the returned sender is dropped before
+fn start_watchdog(seconds: f32, expired: Arc<AtomicBool>) -> mpsc::Sender<()> {
+    let (sender, receiver) = mpsc::channel();
 
-    let len = (image.width * image.height * image.bits_per_pixel / 8) as usize;
-    let image_data = unsafe { std::slice::from_raw_parts_mut(image.data as *mut _, len) };
+    std::thread::spawn(move || {
+        let limit = Duration::from_secs_f32(seconds);
+        if receiver.recv_timeout(limit) == Err(mpsc::RecvTimeoutError::Timeout) {
+            warning!(
+                "The screen was frozen for {} seconds, unfreezing it (see --freeze-duration)",
+                seconds
+            );
+            expired.store(true, Ordering::SeqCst);
+        }
+    });
 
-    // Blend cursor onto the image
-    if args.show_cursor {
-        let cursor = display
-            .get_cursor_image()
-            .context("Failed to get cursor image")?;
-        let pixels = cursor.pixels();
-        let cursorx = cursor.x() as usize - cursor.xhot() as usize;
-        let cursory = cursor.y() as usize - cursor.yhot() as usize;
+    sender
+}
 
-        for x in 0.max(cursorx)..(width as usize).min(cursorx + cursor.width() as usize) {
-            for y in 0.max(cursory)..(height as usize).min(cursory + cursor.height() as usize) {
-                let cx: usize = x - cursorx;
-                let cy: usize = y - cursory;
+/// Blocks until the X connection or one of `fds` is readable or `timeout` passed
+///
+/// All loops while frozen wait through this and check for signals, events and commands after
+/// every wakeup, so returning early for a signal, which interrupts poll, or for input that turns
+/// out to be nothing only costs another iteration.
+fn wait_for_input(display: &Display, fds: &[RawFd], timeout: Duration) {
+    // Requests could be answered by events, and events Xlib already read leave nothing to poll
+    display.flush();
+    if display.has_queued_events() {
+        return;
+    }
 
-                let istart = (y * width as usize + x) as usize * 4;
-                let cstart = cy * cursor.width() as usize + cx;
+    let mut pollfds: Vec<libc::pollfd> = std::iter::once(display.connection_fd())
+        .chain(fds.iter().copied())
+        .map(|fd| libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect();
+    let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
 
-                let alpha = pixels[cstart].a as f32 / 255.0;
+    let result =
+        unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, timeout) };
+    if result == -1 {
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            // Sleeping instead keeps a broken poll from spinning
+            debug!("Failed to wait for input: {}", e);
+            std::thread::sleep(Duration::from_millis(timeout as u64));
+        }
+    }
+}
 
-                let old_b = image_data[istart] as f32 * (1.0 - alpha);
-                let old_g = image_data[istart + 1] as f32 * (1.0 - alpha);
-                let old_r = image_data[istart + 2] as f32 * (1.0 - alpha);
+/// Answers paste requests until another client takes over the clipboard or a signal arrives
+fn serve_clipboard(display: &Display, mut clipboard: Clipboard, signal: &AtomicUsize) {
+    info!("Serving the clipboard until something else is copied");
 
-                image_data[istart] = (old_b + pixels[cstart].b as f32 * alpha) as u8;
-                image_data[istart + 1] = (old_g + pixels[cstart].g as f32 * alpha) as u8;
-                image_data[istart + 2] = (old_r + pixels[cstart].r as f32 * alpha) as u8;
+    while signal.load(Ordering::SeqCst) == 0 {
+        while let Some(event) = display.poll_event() {
+            if !clipboard.handle(&event) {
+                return;
             }
         }
+
+        wait_for_input(display, &[], Duration::from_millis(10));
     }
+}
 
-    let gc_handle = display.create_gc(pixmap_handle);
+/// Runs the executable on top of the freeze and returns the exit code
+///
+/// With --capture-selection its stdout is returned as well.
+fn run_executable(
+    freeze: &mut Freeze,
+    signal: &AtomicUsize,
+    args: &Args,
+    screen_id: usize,
+    image_path: Option<&Path>,
+) -> Result<Outcome> {
+    let window_handle = freeze.frozen.id();
+    let region = freeze.frozen.region();
 
-    display.put_image(
-        pixmap_handle,
-        gc_handle,
-        &mut image,
-        0,
-        0,
-        0,
-        0,
-        width as u32,
-        height as u32,
-    );
+    let executable = &args.executable[0];
+    let mut command = std::process::Command::new(executable);
+    let arguments = args.executable[1..].iter().map(|arg| match image_path {
+        Some(path) => fill_placeholder(arg, path),
+        None => arg.clone(),
+    });
+    command
+        .args(arguments)
+        .env("NORA_WINDOW_ID", window_handle.to_string())
+        .env("NORA_SCREEN_WIDTH", region.width.to_string())
+        .env("NORA_SCREEN_HEIGHT", region.height.to_string());
+    if args.capture_selection {
+        command.stdout(Stdio::piped());
+    } else if args.output.as_deref() == Some(Path::new("-")) {
+        // Keep the image on stdout clean of anything the executable prints
+        let stderr = io::stderr()
+            .as_fd()
+            .try_clone_to_owned()
+            .context("Failed to duplicate stderr")?;
+        command.stdout(stderr);
+    }
+    if let Some(name) = &args.display {
+        command.env("DISPLAY", name);
+    }
 
-    let window_handle = display.create_window(
-        root as i32,
-        0,
-        0,
-        width,
-        height,
-        0,
-        screen.root_depth,
-        x11::xlib::InputOutput,
-        pixmap_handle,
-    );
+    info!("Running {:?}", command);
+    // Returning drops the frozen window, so the screen is unfrozen before the error is printed
+    let mut child = command.spawn().map_err(|e| {
+        let name = executable.to_string_lossy();
+        let hint = match e.kind() {
+            io::ErrorKind::NotFound if !name.contains('/') => {
+                format!("{} was not found in PATH", name)
+            }
+            io::ErrorKind::NotFound => format!("{} does not exist", name),
+            io::ErrorKind::PermissionDenied => format!("{} is not executable", name),
+            _ => return anyhow!(e).context(format!("Failed to execute {}", name)),
+        };
+        anyhow!(e).context(hint)
+    })?;
 
-    display.free_gc(gc_handle);
-    display.free_pixmap(pixmap_handle);
+    // Read on another thread so the executable never blocks on a full pipe
+    let stdout = child.stdout.take().map(|mut stdout| {
+        std::thread::spawn(move || {
+            let mut output = String::new();
+            stdout.read_to_string(&mut output).map(|_| output)
+        })
+    });
 
-    // Setup window properties
-    display.change_property(
-        window_handle,
-        XA_WM_NAME,
-        XA_STRING,
-        PropModeReplace,
-        "fullscreen-viewer".as_bytes(),
-    );
+    if let Some(fd) = args.print_json {
+        let json = format!(
+            "{{\"window_id\":{},\"region\":{{\"x\":{},\"y\":{},\"width\":{},\"height\":{}}},\
+             \"screen\":{},\"child_pid\":{}}}\n",
+            window_handle,
+            region.x,
+            region.y,
+            region.width,
+            region.height,
+            screen_id,
+            child.id()
+        );
 
-    display.change_property(
-        window_handle,
-        XA_WM_CLASS,
-        XA_STRING,
-        PropModeReplace,
-        "fullscreen-viewer\0fullscreen-viewer\0".as_bytes(),
-    );
+        let result = match fd {
+            // Checked to be open by validate and never closed by nora
+            Some(fd) => write_all_fd(unsafe { BorrowedFd::borrow_raw(fd) }, json.as_bytes()),
+            None => io::stderr().write_all(json.as_bytes()),
+        };
+        if let Err(e) = result {
+            warning!("Failed to print the JSON summary: {}", e);
+        }
+    }
+
+    let deadline = args
+        .timeout
+        .map(|timeout| Instant::now() + Duration::from_secs_f32(timeout));
+
+    let mut executable = Executable {
+        child,
+        stdout,
+        deadline,
+    };
+    let code = loop {
+        if let Some(code) = executable.check(signal)? {
+            break code;
+        }
+
+        freeze.update();
+        if freeze.quit {
+            info!("Unfreezing on request, terminating the executable");
+            terminate(&mut executable.child)?;
+            break 130;
+        }
+
+        if freeze.expired.load(Ordering::SeqCst) {
+            if !args.kill_after_freeze {
+                info!("Waiting for the executable to exit after unfreezing");
+                return Ok(Outcome::Outlived(executable));
+            }
+
+            info!("Terminating the executable with the freeze (see --kill-after-freeze)");
+            terminate(&mut executable.child)?;
+            break 128 + SIGTERM;
+        }
+
+        // The exit of the executable does not wake this up, so keep the timeout short
+        freeze.wait(Duration::from_millis(10));
+    };
+
+    Ok(Outcome::Exited(code, executable.output()?))
+}
+
+/// How the freeze ended for what ran during it
+enum Outcome {
+    /// With the exit code and the output of the executable for --capture-selection
+    Exited(i32, Option<String>),
+    /// --freeze-duration unfroze the screen while the executable kept running
+    Outlived(Executable),
+}
+
+/// The executable started by run_executable
+struct Executable {
+    child: Child,
+    stdout: Option<JoinHandle<io::Result<String>>>,
+    // When --timeout terminates it
+    deadline: Option<Instant>,
+}
+
+impl Executable {
+    /// Returns the exit code once the executable exited, a signal arrived or --timeout passed
+    fn check(&mut self, signal: &AtomicUsize) -> Result<Option<i32>> {
+        if let Some(status) = self
+            .child
+            .try_wait()
+            .context("Failed to wait for executable")?
+        {
+            return Ok(Some(exit_code(status)));
+        }
+
+        let sig = signal.load(Ordering::SeqCst) as i32;
+        if sig != 0 {
+            // Pass the signal on so the executable does not outlive nora
+            unsafe { libc::kill(self.child.id() as i32, sig) };
+            return Ok(Some(128 + sig));
+        }
 
-    let atom = display
-        .intern_atom("_NET_WM_BYPASS_COMPOSITOR", false)
-        .context("Failed to get compositor bypass atom")?;
+        if self
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            warning!("The executable timed out, terminating it");
+            terminate(&mut self.child)?;
+            return Ok(Some(124));
+        }
 
-    display.change_property(window_handle, atom, XA_CARDINAL, PropModeReplace, &[1]);
+        Ok(None)
+    }
 
-    // Make window visible
-    display.map_window(window_handle);
+    /// Waits for the executable after the freeze ended without it
+    fn wait(mut self, signal: &AtomicUsize) -> Result<(i32, Option<String>)> {
+        let code = loop {
+            if let Some(code) = self.check(signal)? {
+                break code;
+            }
 
-    // Put window on top
-    display.set_stack_mode(window_handle, x11::xlib::Above);
+            std::thread::sleep(Duration::from_millis(10));
+        };
 
-    // Ensure that commands have completed
-    //conn.flush();
-    display.sync(false);
+        Ok((code, self.output()?))
+    }
 
-    display.set_input_focus(window_handle, RevertToParent, CurrentTime);
+    /// What the executable printed for --capture-selection
+    fn output(self) -> Result<Option<String>> {
+        match self.stdout {
+            Some(reader) => Ok(Some(
+                reader
+                    .join()
+                    .expect("stdout reader panicked")
+                    .context("Failed to read the output of the executable")?,
+            )),
+            None => Ok(None),
+        }
+    }
+}
 
-    let executable = args.executable.remove(0);
-    std::process::Command::new(executable.clone())
-        .args(args.executable)
-        .status()
-        .with_context(|| anyhow!("Failed to execute {}", executable.to_string_lossy()))?;
+/// Writes all of `bytes` to a descriptor nora does not own, retrying on signals
+fn write_all_fd(fd: BorrowedFd<'_>, mut bytes: &[u8]) -> io::Result<()> {
+    while !bytes.is_empty() {
+        let written =
+            unsafe { libc::write(fd.as_raw_fd(), bytes.as_ptr() as *const _, bytes.len()) };
+        match written {
+            0 => return Err(io::ErrorKind::WriteZero.into()),
+            written if written > 0 => bytes = &bytes[written as usize..],
+            _ => {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+        }
+    }
 
     Ok(())
 }
+
+/// Replaces every placeholder in `arg` with `path`
+fn fill_placeholder(arg: &OsStr, path: &Path) -> OsString {
+    let arg_str = match arg.to_str() {
+        Some(arg) => arg,
+        None => return arg.to_owned(),
+    };
+
+    let mut filled = OsString::new();
+    for (i, part) in arg_str.split(PLACEHOLDER).enumerate() {
+        if i > 0 {
+            filled.push(path);
+        }
+        filled.push(part);
+    }
+
+    filled
+}
+
+/// The frozen image saved for --capture-temp, removed when dropped unless it is kept
+struct TempImage {
+    path: PathBuf,
+    keep: bool,
+}
+
+impl TempImage {
+    fn create(frame: &Frame, keep: bool) -> Result<Self> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.subsec_nanos());
+        let name = format!("nora-{}-{}.png", std::process::id(), nanos);
+        let path = std::env::temp_dir().join(name);
+
+        // Only readable by the user since screenshots can contain anything
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .with_context(|| anyhow!("Failed to create {}", path.display()))?;
+        let image = TempImage { path, keep };
+
+        let mut writer = BufWriter::new(file);
+        frame
+            .encode(image::Format::Png, &mut writer)
+            .and_then(|_| writer.flush().map_err(Into::into))
+            .with_context(|| anyhow!("Failed to save image to {}", image.path.display()))?;
+        info!(
+            "Saved the image for the executable to {}",
+            image.path.display()
+        );
+
+        Ok(image)
+    }
+}
+
+impl Drop for TempImage {
+    fn drop(&mut self) {
+        if self.keep {
+            return;
+        }
+
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warning!("Failed to remove {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Saves `frame` to `path` in the format given by the arguments or the extension
+fn save_frame(frame: &Frame, path: &Path, args: &Args) -> Result<()> {
+    // Checked before the file is created
+    frame.bytes_per_pixel().context("Can not save the image")?;
+    let scaled;
+    let frame = if args.scale < 1.0 {
+        scaled = frame
+            .downscale(args.scale)
+            .context("Can not scale the image")?;
+        &scaled
+    } else {
+        frame
+    };
+    let format = match &args.format {
+        Some(name) => image::Format::from_name(name, args.quality),
+        None => path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(|extension| image::Format::from_name(extension, args.quality)),
+    }
+    .unwrap_or(image::Format::Png);
+
+    let mut writer: Box<dyn Write> = if path == Path::new("-") {
+        Box::new(io::stdout().lock())
+    } else {
+        let file =
+            File::create(path).with_context(|| anyhow!("Failed to create {}", path.display()))?;
+        Box::new(BufWriter::new(file))
+    };
+    // Only PNG keeps the byte order, native is whatever the capture has
+    let order = match (format, args.byte_order.as_deref()) {
+        (image::Format::Png, Some(name)) => match image::ByteOrder::from_name(name) {
+            Some(order) => Some(order),
+            None => Some(frame.byte_order()?),
+        },
+        _ => None,
+    };
+
+    match order {
+        Some(order) => frame.encode_png(order, &mut writer),
+        None => frame.encode(format, &mut writer),
+    }
+    .and_then(|_| writer.flush().map_err(Into::into))
+    .with_context(|| anyhow!("Failed to save image to {}", path.display()))
+}
+
+/// Saves the current cursor image to `path` as a PNG with an alpha channel
+#[cfg(feature = "cursor")]
+fn save_cursor(display: &Display, path: &Path) -> Result<()> {
+    if !display.has_xfixes() {
+        bail!("Saving the cursor needs XFixes 2 or newer, which the X server does not support");
+    }
+
+    let cursor = display
+        .get_cursor_image()
+        .context("Failed to get the cursor image")?;
+    info!(
+        "The cursor is {}x{} with its hotspot at {},{}",
+        cursor.width(),
+        cursor.height(),
+        cursor.xhot(),
+        cursor.yhot()
+    );
+    if cursor.width() == 0 || cursor.height() == 0 {
+        bail!("The cursor is hidden, there is no image to save");
+    }
+
+    let rgba: Vec<u8> = cursor
+        .pixels()
+        .iter()
+        .flat_map(|pixel| pixel.to_rgba())
+        .collect();
+
+    let mut writer: Box<dyn Write> = if path == Path::new("-") {
+        Box::new(io::stdout().lock())
+    } else {
+        let file =
+            File::create(path).with_context(|| anyhow!("Failed to create {}", path.display()))?;
+        Box::new(BufWriter::new(file))
+    };
+    image::encode_rgba_png(
+        &rgba,
+        cursor.width() as u32,
+        cursor.height() as u32,
+        &mut writer,
+    )
+    .and_then(|_| writer.flush().map_err(Into::into))
+    .with_context(|| anyhow!("Failed to save the cursor to {}", path.display()))
+}
+
+/// Reads the selection from the first line in `WxH+X+Y` or `X Y W H` format
+fn parse_selection(output: &str) -> Result<Rect> {
+    let line = output
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .context("The executable did not print a selection")?;
+
+    if let Ok(rect) = line.parse() {
+        return Ok(rect);
+    }
+
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    if let [x, y, width, height] = parts[..] {
+        if let (Ok(x), Ok(y), Ok(width), Ok(height)) =
+            (x.parse(), y.parse(), width.parse(), height.parse())
+        {
+            if width > 0 && height > 0 {
+                return Ok(Rect {
+                    x,
+                    y,
+                    width,
+                    height,
+                });
+            }
+        }
+    }
+
+    bail!(
+        "Expected a selection as WxH+X+Y or X Y W H but the executable printed {:?}",
+        line
+    )
+}
+
+/// What the loops running while the screen is frozen work with
+///
+/// The clipboard only borrows the display so it can outlive the frozen window.
+struct Freeze<'a, 'd> {
+    freezer: &'d Freezer,
+    display: &'d Display,
+    root: Window,
+    frozen: &'a FrozenWindow<'d>,
+    frame: Option<&'a Frame>,
+    // Replaces the frame once --control-socket asked for a recapture
+    recaptured: Option<Frame>,
+    args: &'a Args,
+    screen_size: (i32, i32),
+    magnifier: Option<Magnifier>,
+    clipboard: Option<Clipboard<'d>>,
+    selection: Option<Selection>,
+    control: Option<control::ControlSocket>,
+    // When the screensaver was last reset for --inhibit-idle if it could not be suspended
+    idle_reset: Option<Instant>,
+    // Set by the quit command, the loops end like on Escape
+    quit: bool,
+    // Set by the watchdog of --freeze-duration, the loops end the freeze but not the executable
+    expired: Arc<AtomicBool>,
+    keyboard_grabbed: bool,
+    pointer_grabbed: bool,
+}
+
+/// A zoomed in view of the frozen image next to the pointer
+struct Magnifier {
+    zoom: u16,
+    size: u16,
+    pointer: Option<(i32, i32)>,
+    shown: Option<Rect>,
+}
+
+/// The rectangle dragged out with the left button for --select, in screen coordinates
+#[derive(Default)]
+struct Selection {
+    anchor: Option<(i32, i32)>,
+    pointer: (i32, i32),
+    shown: Option<Rect>,
+    done: Option<Rect>,
+}
+
+impl Freeze<'_, '_> {
+    /// Handles the queued events, moves the magnifier and draws the selection, returns whether
+    /// Escape was pressed
+    fn update(&mut self) -> bool {
+        let mut escape = false;
+
+        while let Some(event) = self.display.poll_event() {
+            match event {
+                Event::KeyPress { keysym, time } => {
+                    escape |= keysym == XK_Escape as u64;
+                    if keysym == XK_c as u64 {
+                        self.copy(time);
+                    }
+                }
+                Event::ScreenChange { width, height } => {
+                    warn_screen_change(self.screen_size, width, height)
+                }
+                Event::Expose { .. } => {
+                    // Drawn again on the next update
+                    if let Some(magnifier) = &mut self.magnifier {
+                        magnifier.pointer = None;
+                    }
+                    if let Some(shown) = self.selection.as_mut().and_then(|s| s.shown.take()) {
+                        self.frozen.clear_outline(shown);
+                    }
+                }
+                Event::Button {
+                    button: 1,
+                    pressed,
+                    x,
+                    y,
+                } => {
+                    let region = self.frozen.region();
+                    if let Some(selection) = &mut self.selection {
+                        selection.pointer = (x, y);
+                        if pressed {
+                            selection.anchor = Some((x, y));
+                        } else if let Some(anchor) = selection.anchor.take() {
+                            // A click without dragging starts over
+                            selection.done = Rect::from_corners(anchor, (x, y))
+                                .intersect(region)
+                                .filter(|rect| rect.width > 1 || rect.height > 1);
+                        }
+                    }
+                }
+                Event::Motion { x, y } => {
+                    if let Some(selection) = &mut self.selection {
+                        selection.pointer = (x, y);
+                    }
+                }
+                event => {
+                    let owned = self
+                        .clipboard
+                        .as_mut()
+                        .is_some_and(|clipboard| clipboard.handle(&event));
+                    if !owned {
+                        self.clipboard = None;
+                    }
+                }
+            }
+        }
+
+        if let Some(mut control) = self.control.take() {
+            control.poll(|command| self.run_command(command));
+            self.control = Some(control);
+        }
+
+        if let Some(last) = self.idle_reset {
+            if last.elapsed() >= IDLE_RESET_INTERVAL {
+                self.display.reset_screensaver();
+                self.idle_reset = Some(Instant::now());
+            }
+        }
+
+        self.update_selection();
+        if let Err(e) = self.update_magnifier() {
+            warning!("Disabling the magnifier: {:#}", e);
+            self.magnifier = None;
+        }
+
+        escape
+    }
+
+    /// Waits for input from the X server or the control socket for up to `timeout`
+    fn wait(&self, timeout: Duration) {
+        let fds = self
+            .control
+            .as_ref()
+            .map_or_else(Vec::new, |control| control.fds());
+        wait_for_input(self.display, &fds, timeout);
+    }
+
+    /// Runs a command from --control-socket
+    fn run_command(&mut self, command: control::Command) -> Result<()> {
+        match command {
+            control::Command::Recapture => self.recapture(),
+            control::Command::Save(path) => {
+                let frame = self.frozen.capture()?;
+                info!("Saving the frozen window to {}", path.display());
+                save_frame(&frame, &path, self.args)
+            }
+            control::Command::Quit => {
+                self.quit = true;
+                Ok(())
+            }
+        }
+    }
+
+    /// Freezes what is on screen below the frozen window now instead
+    fn recapture(&mut self) -> Result<()> {
+        let region = self.frozen.region();
+
+        self.frozen.unmap();
+        // The windows below only redraw once they see they are exposed
+        std::thread::sleep(RECAPTURE_DELAY);
+        let frame = capture_frame(
+            self.freezer,
+            Some(region),
+            self.args,
+            &mut Timings::default(),
+        );
+        let shown = frame
+            .as_ref()
+            .map_or(Ok(()), |frame| self.frozen.set_frame(frame));
+        self.frozen.map();
+
+        // Unmapping ended the grabs
+        let window_handle = self.frozen.id();
+        if self.keyboard_grabbed
+            && retry_grab(|| self.display.grab_keyboard(window_handle, CurrentTime)) != GrabSuccess
+        {
+            warning!("Failed to grab the keyboard again");
+        }
+        if self.pointer_grabbed
+            && retry_grab(|| {
+                self.display
+                    .grab_pointer(window_handle, POINTER_EVENTS, CurrentTime)
+            }) != GrabSuccess
+        {
+            warning!("Failed to grab the pointer again");
+        }
+
+        // The new image has neither the magnifier nor the selection on it
+        if let Some(magnifier) = &mut self.magnifier {
+            magnifier.pointer = None;
+            magnifier.shown = None;
+        }
+        if let Some(selection) = &mut self.selection {
+            selection.shown = None;
+        }
+
+        shown?;
+        self.recaptured = Some(frame?);
+        Ok(())
+    }
+
+    /// Puts the frozen image on the clipboard
+    fn copy(&mut self, time: Time) {
+        if self.clipboard.is_some() {
+            return;
+        }
+
+        let frame = match self.recaptured.as_ref().or(self.frame) {
+            Some(frame) => frame,
+            None => {
+                warning!("There is no captured image to copy with --root-fallback");
+                return;
+            }
+        };
+
+        match Clipboard::own(self.display, self.root, frame, time) {
+            Ok(clipboard) => self.clipboard = Some(clipboard),
+            Err(e) => warning!("Failed to copy the image to the clipboard: {:#}", e),
+        }
+    }
+
+    fn update_selection(&mut self) {
+        let selection = match &mut self.selection {
+            Some(selection) => selection,
+            None => return,
+        };
+
+        let region = self.frozen.region();
+        let rect = selection
+            .anchor
+            .and_then(|anchor| Rect::from_corners(anchor, selection.pointer).intersect(region));
+        if rect == selection.shown {
+            return;
+        }
+
+        if let Some(shown) = selection.shown.take() {
+            self.frozen.clear_outline(shown);
+        }
+        if let Some(rect) = rect {
+            self.frozen.outline(rect);
+        }
+        selection.shown = rect;
+
+        self.display.flush();
+    }
+
+    fn update_magnifier(&mut self) -> Result<()> {
+        let (magnifier, frame) =
+            match (&mut self.magnifier, self.recaptured.as_ref().or(self.frame)) {
+                (Some(magnifier), Some(frame)) => (magnifier, frame),
+                _ => return Ok(()),
+            };
+
+        // Polled since the executable usually grabs the pointer
+        let (x, y) = self.display.query_pointer(self.root);
+        if magnifier.pointer == Some((x, y)) {
+            return Ok(());
+        }
+        magnifier.pointer = Some((x, y));
+
+        if let Some(shown) = magnifier.shown.take() {
+            self.frozen.clear(shown);
+        }
+
+        let region = frame.region();
+        if region.contains(x, y) {
+            // Keep the pointer itself visible by flipping to the other side near the edges
+            const OFFSET: i32 = 16;
+            let size = magnifier.size as i32;
+            let left = if x + OFFSET + size <= region.right() {
+                x + OFFSET
+            } else {
+                x - OFFSET - size
+            };
+            let top = if y + OFFSET + size <= region.bottom() {
+                y + OFFSET
+            } else {
+                y - OFFSET - size
+            };
+
+            let target = Rect {
+                x: left as i16,
+                y: top as i16,
+                width: magnifier.size,
+                height: magnifier.size,
+            }
+            .clamp_to(region);
+
+            let loupe = frame.magnify(x, y, magnifier.zoom, target)?;
+            self.frozen.draw(&loupe)?;
+            magnifier.shown = Some(target);
+        }
+
+        // The loupe covers or clears parts of the selection
+        if let Some(shown) = self
+            .selection
+            .as_ref()
+            .and_then(|selection| selection.shown)
+        {
+            self.frozen.outline(shown);
+        }
+
+        self.display.flush();
+        Ok(())
+    }
+}
+
+/// Cuts a monitor down to the part on the screen
+///
+/// RandR can still report CRTCs the screen was shrunk away from, capturing outside of the root
+/// window fails.
+fn clip_monitor(screen: Rect, monitor: Rect) -> Result<Rect> {
+    let visible = screen
+        .intersect(monitor)
+        .with_context(|| anyhow!("The monitor at {} is not on the screen", monitor))?;
+    if visible != monitor {
+        warning!(
+            "The monitor at {} is only partly on the {}x{} screen, freezing {}",
+            monitor,
+            screen.width,
+            screen.height,
+            visible
+        );
+    }
+
+    Ok(visible)
+}
+
+fn warn_screen_change(screen_size: (i32, i32), width: i32, height: i32) {
+    if (width, height) != screen_size {
+        warning!(
+            "The screen changed to {}x{} while frozen, the {}x{} freeze no longer matches it",
+            width,
+            height,
+            screen_size.0,
+            screen_size.1
+        );
+    }
+}
+
+/// Sends SIGTERM to the child and SIGKILL if it is still running a second later
+fn terminate(child: &mut Child) -> Result<()> {
+    unsafe { libc::kill(child.id() as i32, SIGTERM) };
+
+    let deadline = Instant::now() + Duration::from_secs(1);
+    while Instant::now() < deadline {
+        if child
+            .try_wait()
+            .context("Failed to wait for executable")?
+            .is_some()
+        {
+            return Ok(());
+        }
+
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    debug!("The executable ignored SIGTERM, killing it");
+    child.kill().context("Failed to kill executable")?;
+    child.wait().context("Failed to wait for executable")?;
+    Ok(())
+}
+
+/// Warns when the monitors do not cover exactly the screen
+///
+/// That happens with a stale mode and the freeze would end up misaligned.
+fn check_monitor_layout(display: &Display, root: Window, width: i32, height: i32) {
+    let monitors = match display.monitors(root) {
+        Ok(monitors) if !monitors.is_empty() => monitors,
+        Ok(_) => return,
+        Err(e) => {
+            debug!("Skipping the monitor layout check: {:#}", e);
+            return;
+        }
+    };
+
+    for monitor in &monitors {
+        debug!("Monitor {} at {}", monitor.name, monitor.rect);
+    }
+
+    let left = monitors.iter().map(|m| m.rect.x as i32).min().unwrap_or(0);
+    let top = monitors.iter().map(|m| m.rect.y as i32).min().unwrap_or(0);
+    let right = monitors.iter().map(|m| m.rect.right()).max().unwrap_or(0);
+    let bottom = monitors.iter().map(|m| m.rect.bottom()).max().unwrap_or(0);
+
+    if left != 0 || top != 0 || right != width || bottom != height {
+        warning!(
+            "The monitors span {}x{}+{}+{} but the screen is {}x{}, the freeze may be \
+             misaligned",
+            right - left,
+            bottom - top,
+            left,
+            top,
+            width,
+            height
+        );
+    }
+
+    // A single GPU only has unused outputs that are turned off
+    if display.provider_count(root).unwrap_or(0) > 1 {
+        for name in display.unused_outputs(root).unwrap_or_default() {
+            warning!(
+                "Output {} is connected but not part of the screen, if it belongs to another GPU \
+                 it needs xrandr --setprovideroutputsource to show up in the freeze",
+                name
+            );
+        }
+    }
+}
+
+/// Maps the status of the executable to an exit code the same way shells do
+fn exit_code(status: ExitStatus) -> i32 {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => 1,
+    }
+}
+
+fn parse_seconds(src: &str) -> Result<f32> {
+    let seconds = src.parse::<f32>()?;
+    if !seconds.is_finite() || seconds < 0.0 {
+        bail!("expected a positive number of seconds");
+    }
+
+    Ok(seconds)
+}
+
+/// Rejects negative file descriptors for --print-json
+///
+/// A validator since structopt ignores the optional value of a field with a custom parser.
+fn validate_fd(src: String) -> Result<(), String> {
+    match src.parse::<RawFd>() {
+        Ok(fd) if fd >= 0 => Ok(()),
+        _ => Err("expected a file descriptor of 0 or more".into()),
+    }
+}
+
+/// Parses a window XID in decimal or in hex with a 0x prefix like xwininfo prints it
+fn parse_xid(src: &str) -> Result<Window> {
+    let xid = match src.strip_prefix("0x") {
+        Some(hex) => Window::from_str_radix(hex, 16)?,
+        None => src.parse()?,
+    };
+
+    Ok(xid)
+}
+
+/// Parses the top, bottom, left and right monitor index of --monitors
+fn parse_monitor_indices(src: &str) -> Result<[u32; 4]> {
+    let indices = src
+        .split(',')
+        .map(|index| index.trim().parse::<u32>())
+        .collect::<Result<Vec<_>, _>>()
+        .context("expected monitor indices")?;
+
+    match indices[..] {
+        [top, bottom, left, right] => Ok([top, bottom, left, right]),
+        _ => bail!("expected four indices as top,bottom,left,right"),
+    }
+}
+
+/// Parses the WIDTH:RRGGBB of --border
+fn parse_border(src: &str) -> Result<(u16, [u8; 3])> {
+    let (width, color) = src.split_once(':').context("expected WIDTH:RRGGBB")?;
+    let width = width.parse::<u16>().context("invalid border width")?;
+    if width == 0 {
+        bail!("the border width has to be larger than 0");
+    }
+
+    let hex = color.strip_prefix('#').unwrap_or(color);
+    if hex.len() != 6 || !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+        bail!("expected the border color as RRGGBB in hex");
+    }
+    let rgb = u32::from_str_radix(hex, 16)?;
+
+    Ok((width, [(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8]))
+}
+
+fn parse_quality(src: &str) -> Result<u8> {
+    let quality = src.parse::<u8>()?;
+    if !(1..=100).contains(&quality) {
+        bail!("expected a number between 1 and 100");
+    }
+
+    Ok(quality)
+}
+
+fn parse_factor(src: &str) -> Result<f32> {
+    let factor = src.parse::<f32>()?;
+    if !(0.0..=1.0).contains(&factor) {
+        bail!("expected a number between 0 and 1");
+    }
+
+    Ok(factor)
+}
+
+/// Parses the --count of --repeat, which has to run the executable at least once
+fn parse_count(src: &str) -> Result<u32> {
+    let count = src.parse::<u32>()?;
+    if count == 0 {
+        bail!("expected a count of 1 or more");
+    }
+
+    Ok(count)
+}
+
+/// Runs `grab` until it stops failing with `AlreadyGrabbed` or a second passed
+///
+/// The keys used to launch nora might still be grabbed by a hotkey daemon.
+fn retry_grab(grab: impl Fn() -> i32) -> i32 {
+    let mut status = grab();
+    for _ in 0..100 {
+        if status != AlreadyGrabbed {
+            break;
+        }
+
+        std::thread::sleep(Duration::from_millis(10));
+        status = grab();
+    }
+
+    status
+}
+
+/// Moves the pointer into `region` if it is outside and keeps it there until the barriers
+/// are dropped
+#[cfg(feature = "cursor")]
+fn confine_pointer(
+    display: &Display,
+    root: Window,
+    region: Rect,
+) -> Option<nora::ffi::PointerBarriers<'_>> {
+    let (x, y) = display.query_pointer(root);
+    if !region.contains(x, y) {
+        let x = x.clamp(region.x as i32, region.right() - 1);
+        let y = y.clamp(region.y as i32, region.bottom() - 1);
+        display.warp_pointer(root, x, y);
+    }
+
+    match display.confine_pointer(root, region) {
+        Ok(barriers) => Some(barriers),
+        Err(e) => {
+            warning!("Not keeping the pointer inside the frozen area: {:#}", e);
+            None
+        }
+    }
+}
+
+/// Opens the display, retrying as often and as long as --connect-retries and --connect-timeout
+/// allow
+fn connect(args: &Args) -> Result<Freezer> {
+    let deadline = args
+        .connect_timeout
+        .map(|timeout| Instant::now() + Duration::from_secs_f32(timeout));
+    let mut delay = Duration::from_millis(100);
+    let mut attempt = 0;
+
+    loop {
+        let error = match Freezer::new(args.display.as_deref()) {
+            Ok(freezer) => return Ok(freezer),
+            Err(e) => e,
+        };
+
+        attempt += 1;
+        let remaining = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        let retry = attempt <= args.connect_retries
+            || remaining.is_some_and(|remaining| !remaining.is_zero());
+        if !retry {
+            return Err(error);
+        }
+
+        info!(
+            "Connection attempt {} failed, retrying: {:#}",
+            attempt, error
+        );
+        std::thread::sleep(remaining.map_or(delay, |remaining| remaining.min(delay)));
+        delay = (delay * 2).min(Duration::from_secs(2));
+    }
+}
+
+/// Prints the versions of the extensions and what they are needed for
+fn print_features(display: &Display) -> Result<()> {
+    let mut stdout = io::stdout().lock();
+    writeln!(stdout, "nora {}", env!("CARGO_PKG_VERSION")).context("Failed to print features")?;
+
+    for extension in display.extensions() {
+        let version = match extension.version {
+            Some((major, minor)) => format!("{}.{}", major, minor),
+            None if extension.name == "XFIXES" && !cfg!(feature = "cursor") => {
+                "disabled".to_string()
+            }
+            None => "missing".to_string(),
+        };
+        let purpose = match extension.name {
+            "XFIXES" => "drawing the cursor",
+            "MIT-SHM" => "capturing through shared memory",
+            "RANDR" => "--monitor, --list-monitors and screen change warnings",
+            "XINERAMA" => "--monitor and --list-monitors without RandR",
+            "MIT-SCREEN-SAVER" => "suspending the screensaver for --inhibit-idle",
+            "BIG-REQUESTS" => "uploading the frozen image in fewer requests",
+            _ => "",
+        };
+
+        writeln!(stdout, "{:<18}{:<9}{}", extension.name, version, purpose)
+            .context("Failed to print features")?;
+    }
+
+    Ok(())
+}
+
+/// Returns the area spanned by the monitors with the top, bottom, left and right `indices`
+fn monitors_rect(display: &Display, root: Window, indices: [u32; 4]) -> Result<Rect> {
+    let monitors = display.monitors(root).context("Failed to query monitors")?;
+    let [top, bottom, left, right] = indices.map(|index| monitors.get(index as usize));
+    let (top, bottom, left, right) = match (top, bottom, left, right) {
+        (Some(top), Some(bottom), Some(left), Some(right)) => (top, bottom, left, right),
+        _ => bail!(
+            "--monitors needs indices below {}, see --list-monitors",
+            monitors.len()
+        ),
+    };
+
+    let (x, y) = (left.rect.x as i32, top.rect.y as i32);
+    let (width, height) = (right.rect.right() - x, bottom.rect.bottom() - y);
+    if width <= 0 || height <= 0 {
+        bail!(
+            "The monitors {}, {}, {} and {} do not span an area in that order",
+            top.name,
+            bottom.name,
+            left.name,
+            right.name
+        );
+    }
+
+    Ok(Rect {
+        x: x as i16,
+        y: y as i16,
+        width: width as u16,
+        height: height as u16,
+    })
+}
+
+/// Prints the monitors in the order --monitor counts them
+fn list_monitors(display: &Display, root: Window) -> Result<()> {
+    let monitors = display.monitors(root).context("Failed to query monitors")?;
+
+    let mut stdout = io::stdout().lock();
+    for (index, monitor) in monitors.iter().enumerate() {
+        let primary = if monitor.primary { " primary" } else { "" };
+        writeln!(
+            stdout,
+            "{}: {} {}{}",
+            index, monitor.name, monitor.rect, primary
+        )
+        .context("Failed to print the monitors")?;
+    }
+
+    Ok(())
+}
+
+/// Saves the part of `frame` on each monitor into `dir`, named after the monitor
+fn save_monitors(
+    display: &Display,
+    root: Window,
+    frame: &Frame,
+    dir: &Path,
+    args: &Args,
+) -> Result<()> {
+    let monitors = display.monitors(root).context("Failed to query monitors")?;
+    std::fs::create_dir_all(dir).with_context(|| anyhow!("Failed to create {}", dir.display()))?;
+    let extension = args.format.as_deref().unwrap_or("png");
+
+    let mut saved = 0;
+    for monitor in &monitors {
+        // Monitors of outputs that are off have no area on the screen
+        let visible = match monitor.rect.intersect(frame.region()) {
+            Some(visible) => visible,
+            None => {
+                debug!("Skipping {}, it is not on the screen", monitor.name);
+                continue;
+            }
+        };
+
+        // Output names never contain a slash, but keep it in the directory regardless
+        let name = monitor.name.replace('/', "_");
+        let path = dir.join(format!("{}.{}", name, extension));
+        save_frame(&frame.crop(visible)?, &path, args)?;
+        saved += 1;
+    }
+
+    if saved == 0 {
+        warning!("None of the monitors are on the screen, nothing was saved");
+    } else if !args.quiet {
+        eprintln!("Saved {} monitors to {}", saved, dir.display());
+    }
+
+    Ok(())
+}
+
+fn find_monitor(display: &Display, root: Window, query: &str) -> Result<Monitor> {
+    let mut monitors = display.monitors(root).context("Failed to query monitors")?;
+
+    let index = if query == "current" {
+        let (pointer_x, pointer_y) = display.query_pointer(root);
+        monitors
+            .iter()
+            .position(|m| m.rect.contains(pointer_x, pointer_y))
+    } else if query == "active" {
+        let window = display.active_window(root)?;
+        let rect = display
+            .window_rect(window)
+            .context("Failed to get the position of the active window")?;
+
+        // The window can span several monitors
+        monitors
+            .iter()
+            .enumerate()
+            .filter_map(|(i, m)| Some((i, m.rect.intersect(rect)?)))
+            .max_by_key(|(_, area)| area.width as u32 * area.height as u32)
+            .map(|(i, _)| i)
+    } else if let Ok(index) = query.parse::<usize>() {
+        Some(index).filter(|&i| i < monitors.len())
+    } else {
+        monitors.iter().position(|m| m.name == query)
+    };
+
+    match index {
+        Some(index) => Ok(monitors.swap_remove(index)),
+        None => bail!("Monitor {} not found", query),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(x: i16, y: i16, width: u16, height: u16) -> Rect {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn parse_selection_reads_both_formats() {
+        assert_eq!(
+            parse_selection("100x50+10+20").unwrap(),
+            rect(10, 20, 100, 50)
+        );
+        assert_eq!(
+            parse_selection("10 20 100 50").unwrap(),
+            rect(10, 20, 100, 50)
+        );
+    }
+
+    #[test]
+    fn parse_selection_reads_negative_offsets() {
+        assert_eq!(
+            parse_selection("100x50+-10+-20").unwrap(),
+            rect(-10, -20, 100, 50)
+        );
+        assert_eq!(
+            parse_selection("-10 -20 100 50").unwrap(),
+            rect(-10, -20, 100, 50)
+        );
+    }
+
+    #[test]
+    fn parse_selection_skips_whitespace_and_empty_lines() {
+        assert_eq!(
+            parse_selection("\n  100x50+10+20 \t\n").unwrap(),
+            rect(10, 20, 100, 50)
+        );
+        assert_eq!(
+            parse_selection(" 10  20\t100 50\r\nignored\n").unwrap(),
+            rect(10, 20, 100, 50)
+        );
+    }
+
+    #[test]
+    fn parse_selection_rejects_malformed_output() {
+        for output in [
+            "100x50+10",
+            "10 20 100",
+            "10 20 0 50",
+            "10 20 100 50 5",
+            "none",
+        ] {
+            let error = parse_selection(output).unwrap_err().to_string();
+            assert!(
+                error.starts_with("Expected a selection"),
+                "{:?}: {}",
+                output,
+                error
+            );
+        }
+
+        let error = parse_selection(" \n\n").unwrap_err().to_string();
+        assert_eq!(error, "The executable did not print a selection");
+    }
+
+    #[test]
+    fn apply_effects_tints_the_gray_image() {
+        let args = Args::from_iter_safe(["nora", "--grayscale", "--tint", "ff000080"]).unwrap();
+        let region = rect(0, 0, 1, 1);
+        let mut frame = Frame::new(vec![100, 150, 200, 7], region, 4, 24, 32).unwrap();
+        apply_effects(&mut frame, &args).unwrap();
+
+        // A luminance of 157 with half of the red tint over it
+        assert_eq!(frame.data(), [78, 78, 206, 7]);
+    }
+
+    #[test]
+    fn parse_border_reads_the_width_and_color() {
+        assert_eq!(parse_border("3:ff8000").unwrap(), (3, [0xff, 0x80, 0x00]));
+        assert_eq!(
+            parse_border("12:#00A0fF").unwrap(),
+            (12, [0x00, 0xa0, 0xff])
+        );
+    }
+
+    #[test]
+    fn parse_border_rejects_bad_colors() {
+        for border in [
+            "3:ff80",
+            "3:ff80001",
+            "3:gg8000",
+            "3:+f8000",
+            "3:##ff8000",
+            "3:",
+        ] {
+            let error = parse_border(border).unwrap_err().to_string();
+            assert_eq!(
+                error, "expected the border color as RRGGBB in hex",
+                "{:?}",
+                border
+            );
+        }
+    }
+
+    #[test]
+    fn parse_border_needs_a_width() {
+        assert_eq!(
+            parse_border("ff8000").unwrap_err().to_string(),
+            "expected WIDTH:RRGGBB"
+        );
+        assert_eq!(
+            parse_border(":ff8000").unwrap_err().to_string(),
+            "invalid border width"
+        );
+        assert_eq!(
+            parse_border("0:ff8000").unwrap_err().to_string(),
+            "the border width has to be larger than 0"
+        );
+    }
+
+    /// The arguments after `cli` with the defaults of `config` filled in
+    fn configured(cli: &[&str], config: &str) -> Args {
+        let matches = Args::clap()
+            .get_matches_from_safe(std::iter::once("nora").chain(cli.iter().copied()))
+            .unwrap();
+        let mut args = Args::from_clap(&matches);
+        args.apply_config(toml::from_str(config).unwrap(), &matches)
+            .unwrap();
+        args
+    }
+
+    #[test]
+    fn apply_config_prefers_the_command_line() {
+        let config = "dim = 0.6\ndelay = 2\nexecutable = [\"maim\", \"-s\"]";
+
+        let args = configured(&["--dim", "0.3", "slop"], config);
+        assert_eq!(args.dim, 0.3);
+        assert_eq!(args.delay, 2.0);
+        assert_eq!(args.executable, ["slop"]);
+
+        let args = configured(&[], config);
+        assert_eq!(args.dim, 0.6);
+        assert_eq!(args.executable, ["maim", "-s"]);
+    }
+
+    #[test]
+    fn apply_config_leaves_out_the_monitor_when_an_area_is_given() {
+        let config = "monitor = \"current\"";
+
+        assert_eq!(
+            configured(&["slop"], config).monitor.as_deref(),
+            Some("current")
+        );
+        for area in [
+            &["--monitor", "1"][..],
+            &["--geometry", "10x10"],
+            &["--output-per-monitor", "/tmp"],
+        ] {
+            let args = configured(&[area, &["slop"]].concat(), config);
+            assert_ne!(args.monitor.as_deref(), Some("current"), "{:?}", area);
+        }
+    }
+
+    #[test]
+    fn count_has_to_be_at_least_one() {
+        let count = |count| {
+            Args::clap()
+                .get_matches_from_safe(["nora", "--repeat", "--count", count, "slop"])
+                .map(|matches| Args::from_clap(&matches).count)
+        };
+
+        assert_eq!(count("3").unwrap(), Some(3));
+        let error = count("0").unwrap_err();
+        assert_eq!(error.kind, clap::ErrorKind::ValueValidation);
+        assert!(error.message.contains("expected a count of 1 or more"));
+    }
+}